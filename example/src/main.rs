@@ -14,10 +14,20 @@ fn main() {
         .with_key_selector(|row| row.get(2).unwrap_or("language_unknown").to_string())
         .with_output_shard_naming(|key, seq| format!("data_lang={key}.part{seq}.csv"))
         .with_output_splitting(FileSplitting::SplitAfterBytes(1024 * 1024))
-        .on_file_completion(|path, key| {
-            println!("The file {} is now ready for shard {key}", path.display());
+        .on_file_completion(|file| {
+            println!("The file {} is now ready for shard {}", file.path.display(), file.key);
             // Upload the file to our remote server or something.
         });
 
-    writer.process_csv(&mut reader).ok();
+    // Processing stops with an error at the first row that can't be parsed.
+    writer
+        .process_csv(&mut reader)
+        .expect("Failed to process input");
+
+    // Flush and close every output file, making sure nothing failed along the way.
+    let summary = writer.finish().expect("Failed to close output files");
+    println!(
+        "Wrote {} records to {} files",
+        summary.records_written, summary.files_written
+    );
 }
@@ -0,0 +1,167 @@
+use crate::Error;
+use csv::{ByteRecord, StringRecord};
+use std::io::{Read, Seek, SeekFrom};
+
+/// How many bytes of already-returned rows [RawInput] lets build up before discarding them.
+const RAW_INPUT_DISCARD: usize = 64 * 1024;
+
+/// A row read from an input, which may or may not have parsed cleanly.
+pub(crate) enum InputRow {
+    /// A row that parsed into a valid [StringRecord]
+    Record {
+        record: StringRecord,
+
+        /// The row's bytes as they appeared in the input, if they were captured
+        raw: Option<Vec<u8>>,
+    },
+
+    /// A row that couldn't be parsed, along with whatever raw fields were read for it
+    Malformed {
+        /// The one-based line on which the row started
+        line: u64,
+
+        /// Why the row couldn't be parsed
+        error: Box<dyn std::error::Error + Send + Sync>,
+
+        /// The raw, unvalidated fields of the row
+        record: ByteRecord,
+
+        /// The row's bytes as they appeared in the input, if they were captured
+        raw: Option<Vec<u8>>,
+    },
+}
+
+/// Wraps an input so that the original bytes of each row can be recovered after the CSV reader
+/// has parsed it. Nothing is kept unless `capture` is set.
+pub(crate) struct RawInput<R> {
+    inner: R,
+    capture: bool,
+
+    /// Bytes read through this input that haven't been discarded yet
+    buffer: Vec<u8>,
+
+    /// The offset in the input of the first byte in `buffer`
+    start: u64,
+}
+
+impl<R> RawInput<R> {
+    pub fn new(inner: R, capture: bool) -> Self {
+        Self {
+            inner,
+            capture,
+            buffer: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// Returns the bytes of the input from offset `from` up to `to`, less any line breaks at
+    /// either end. Bytes before `to` are no longer needed after this.
+    fn take(&mut self, from: u64, to: u64) -> Option<Vec<u8>> {
+        if !self.capture {
+            return None;
+        }
+
+        let from = from.saturating_sub(self.start) as usize;
+        let to = (to.saturating_sub(self.start) as usize).min(self.buffer.len());
+        let raw = trim_line_breaks(&self.buffer[from.min(to)..to]).to_vec();
+
+        if to >= RAW_INPUT_DISCARD {
+            self.buffer.drain(..to);
+            self.start += to as u64;
+        }
+
+        Some(raw)
+    }
+}
+
+impl<R: Read> Read for RawInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.capture {
+            self.buffer.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for RawInput<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = self.inner.seek(pos)?;
+        self.buffer.clear();
+        self.start = offset;
+        Ok(offset)
+    }
+}
+
+/// Iterates over every row of `reader`, separating malformed rows from valid ones.
+///
+/// Ragged rows and rows that aren't valid UTF-8 are yielded as [InputRow::Malformed] so the
+/// caller can decide what to do with them. I/O errors can't be skipped past, so they are
+/// yielded as errors, after which iteration ends.
+pub(crate) fn rows<R: Read>(
+    reader: &mut csv::Reader<R>,
+) -> impl Iterator<Item = Result<InputRow, Error>> + '_ {
+    read_rows(reader, |_, _| None)
+}
+
+/// Iterates over every row of `reader` as [rows] does, along with the bytes each row was read
+/// from if the [RawInput] is capturing them.
+pub(crate) fn raw_rows<R: Read>(
+    reader: &mut csv::Reader<RawInput<R>>,
+) -> impl Iterator<Item = Result<InputRow, Error>> + '_ {
+    read_rows(reader, |reader, from| {
+        let to = reader.position().byte();
+        reader.get_mut().take(from, to)
+    })
+}
+
+fn read_rows<R, F>(
+    reader: &mut csv::Reader<R>,
+    mut raw: F,
+) -> impl Iterator<Item = Result<InputRow, Error>> + '_
+where
+    R: Read,
+    F: FnMut(&mut csv::Reader<R>, u64) -> Option<Vec<u8>> + 'static,
+{
+    std::iter::from_fn(move || {
+        let mut record = ByteRecord::new();
+        let line = |record: &ByteRecord| record.position().map_or(0, |p| p.line());
+        let result = reader.read_byte_record(&mut record);
+        let from = record.position().map_or(0, |p| p.byte());
+
+        match result {
+            Ok(false) => None,
+            Ok(true) => {
+                let raw = raw(reader, from);
+                Some(Ok(match StringRecord::from_byte_record(record) {
+                    Ok(record) => InputRow::Record { record, raw },
+                    Err(e) => {
+                        let error = Box::new(e.utf8_error().clone());
+                        let record = e.into_byte_record();
+                        InputRow::Malformed {
+                            line: line(&record),
+                            error,
+                            record,
+                            raw,
+                        }
+                    }
+                }))
+            }
+            Err(e) if e.is_io_error() => Some(Err(e.into())),
+            Err(e) => Some(Ok(InputRow::Malformed {
+                line: line(&record),
+                error: Box::new(e),
+                raw: raw(reader, from),
+                record,
+            })),
+        }
+    })
+}
+
+/// Trims the line breaks either side of a row's bytes.
+fn trim_line_breaks(bytes: &[u8]) -> &[u8] {
+    let is_text = |b: &u8| !matches!(b, b'\r' | b'\n');
+    let start = bytes.iter().position(is_text).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(is_text).map_or(start, |i| i + 1);
+    &bytes[start..end]
+}
@@ -0,0 +1,211 @@
+use csv::StringRecord;
+
+/// An error returned by a [KeySelector] that couldn't determine a record's key.
+pub type KeyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Determines which shard or shards each record belongs to.
+///
+/// Keys may be of any type that is `Hash + Eq + Clone + Display`, such as a `String`, an
+/// integer customer ID or an enum; the typed key is passed to the file naming closure and the
+/// completion callback, so there's no need to format it on every row.
+///
+/// You rarely need to implement this yourself: any closure of the form
+/// `Fn(&StringRecord) -> K` is a key selector that places each record in exactly one
+/// shard, [OptionalKey] wraps closures that may drop records, [TryKey] wraps closures that may
+/// fail, and [MultiKey] wraps closures that place records in several.
+///
+/// A record for which no keys are selected isn't written anywhere and is counted in
+/// [`crate::ProcessSummary::records_filtered`].
+pub trait KeySelector {
+    /// The type of key identifying each shard
+    type Key;
+
+    /// Appends the key of every shard that `record` should be written to.
+    ///
+    /// Returning an error means the record can't be sharded; what happens then is determined by
+    /// the writer's [`crate::MalformedRowPolicy`].
+    fn select_keys(&self, record: &StringRecord, keys: &mut Vec<Self::Key>)
+        -> Result<(), KeyError>;
+}
+
+impl<F, K> KeySelector for F
+where
+    F: Fn(&StringRecord) -> K,
+{
+    type Key = K;
+
+    fn select_keys(
+        &self,
+        record: &StringRecord,
+        keys: &mut Vec<Self::Key>,
+    ) -> Result<(), KeyError> {
+        keys.push(self(record));
+        Ok(())
+    }
+}
+
+/// A key selector that fans each record out to any number of shards.
+///
+/// The wrapped closure returns every key the record belongs to, eg, one per tag in a
+/// semicolon-separated column. The record is written once per key returned, so a key returned
+/// twice writes the record twice; a record for which no keys are returned is filtered out.
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("id,name,email,tags\n".as_bytes());
+/// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+///     .with_multi_key_selector(|rec| {
+///         rec.get(3).unwrap_or_default().split(';').map(str::to_owned).collect::<Vec<_>>()
+///     });
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+pub struct MultiKey<F>(pub F);
+
+impl<F, I> KeySelector for MultiKey<F>
+where
+    F: Fn(&StringRecord) -> I,
+    I: IntoIterator,
+{
+    type Key = I::Item;
+
+    fn select_keys(
+        &self,
+        record: &StringRecord,
+        keys: &mut Vec<Self::Key>,
+    ) -> Result<(), KeyError> {
+        keys.extend((self.0)(record));
+        Ok(())
+    }
+}
+
+/// A key selector that can drop records by returning `None`.
+///
+/// Records for which the wrapped closure returns `None` are filtered out without creating a
+/// shard, which saves routing unwanted rows to a sentinel shard and deleting it afterwards.
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("id,name,team\n".as_bytes());
+/// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+///     .with_optional_key_selector(|rec| rec.get(2).filter(|s| !s.is_empty()).map(str::to_owned));
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+pub struct OptionalKey<F>(pub F);
+
+impl<F, K> KeySelector for OptionalKey<F>
+where
+    F: Fn(&StringRecord) -> Option<K>,
+{
+    type Key = K;
+
+    fn select_keys(
+        &self,
+        record: &StringRecord,
+        keys: &mut Vec<Self::Key>,
+    ) -> Result<(), KeyError> {
+        keys.extend((self.0)(record));
+        Ok(())
+    }
+}
+
+/// A key selector that can fail, eg, because the column being sharded on doesn't parse.
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("id,name,team,email,year\n".as_bytes());
+/// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+///     .with_try_key_selector(|rec| {
+///         let year: u16 = rec.get(4).unwrap_or_default().parse()?;
+///         Ok::<_, std::num::ParseIntError>(year.to_string())
+///     });
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+pub struct TryKey<F>(pub F);
+
+impl<F, K, E> KeySelector for TryKey<F>
+where
+    F: Fn(&StringRecord) -> Result<K, E>,
+    E: Into<KeyError>,
+{
+    type Key = K;
+
+    fn select_keys(
+        &self,
+        record: &StringRecord,
+        keys: &mut Vec<Self::Key>,
+    ) -> Result<(), KeyError> {
+        keys.push((self.0)(record).map_err(Into::into)?);
+        Ok(())
+    }
+}
+
+/// A key selector that uses the value of a single column as the key.
+///
+/// This is usually created by name with [`crate::ShardedWriterBuilder::key_by_column`], which
+/// looks the column up in the header once, up front. A record that's too short to have the
+/// column is an error.
+#[derive(Clone, Debug)]
+pub struct ColumnKey {
+    index: usize,
+}
+
+impl ColumnKey {
+    /// Creates a key selector for the zero-based column `index`.
+    pub fn new(index: usize) -> Self {
+        Self { index }
+    }
+}
+
+impl KeySelector for ColumnKey {
+    type Key = String;
+
+    fn select_keys(&self, record: &StringRecord, keys: &mut Vec<String>) -> Result<(), KeyError> {
+        keys.push(field(record, self.index)?.to_owned());
+        Ok(())
+    }
+}
+
+/// A key selector that joins the values of several columns into the key.
+///
+/// This is usually created by name with [`crate::ShardedWriterBuilder::key_by_columns`]. For
+/// example, sharding on `country` and `state` with a separator of `"/"` gives keys like
+/// `"US/WA"`.
+#[derive(Clone, Debug)]
+pub struct ColumnsKey {
+    indices: Vec<usize>,
+    separator: String,
+}
+
+impl ColumnsKey {
+    /// Creates a key selector joining the zero-based columns `indices` with `separator`.
+    pub fn new(indices: Vec<usize>, separator: &str) -> Self {
+        Self {
+            indices,
+            separator: separator.to_owned(),
+        }
+    }
+}
+
+impl KeySelector for ColumnsKey {
+    type Key = String;
+
+    fn select_keys(&self, record: &StringRecord, keys: &mut Vec<String>) -> Result<(), KeyError> {
+        let mut key = String::new();
+        for (i, &index) in self.indices.iter().enumerate() {
+            if i > 0 {
+                key.push_str(&self.separator);
+            }
+            key.push_str(field(record, index)?);
+        }
+
+        keys.push(key);
+        Ok(())
+    }
+}
+
+/// Gets the field at `index`, failing if the record is too short to have it.
+pub(crate) fn field(record: &StringRecord, index: usize) -> Result<&str, KeyError> {
+    record
+        .get(index)
+        .ok_or_else(|| format!("record has {} fields; no column {index}", record.len()).into())
+}
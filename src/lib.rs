@@ -5,6 +5,15 @@
 //! output file. Multiple files can be streamed through the same `ShardedWriter`
 //! provided they are of the same schema.
 //!
+//! A `ShardedWriter` is [`Send`] as long as its key selector and naming closures are, so it can
+//! be moved into a worker thread to run several sharding jobs at once.
+//!
+//! The hooks given to `.on_file_completion` and `.on_create_file`, and the writers
+//! `.on_create_file` returns, are stored boxed, so they must always be `Send`; without that, no
+//! writer using them could be moved to another thread. This is a breaking change for callers
+//! whose hooks capture `Rc<RefCell<_>>` state: use `Arc<Mutex<_>>` or send to a
+//! [`std::sync::mpsc`] channel instead, as in the example under "File completion notification".
+//!
 //! # Current Limitations
 //! * Input and output formats are limited to delimited (eg, CSV, TSV) formats, making
 //!   use of the [`csv` crate](https://crates.io/crates/csv).
@@ -13,7 +22,7 @@
 //! Because `shard-csv` intimately deals with CSV data, it pulls in the `csv` crate and
 //! re-exports it for callers as `shard_csv::csv`:
 //!
-//! ```
+//! ```ignore
 //! let mut csv_reader = shard_csv::csv::ReaderBuilder::new()
 //!    .delimiter(b',')
 //!    .has_headers(true)
@@ -24,7 +33,7 @@
 //! Several functions make use of the `csv::Reader` type, but you can write data without
 //! it provided each row is converted to a `StringRecord`:
 //!
-//! ```
+//! ```ignore
 //! let data = vec![
 //!     ["john", "smith", "123 main st"],
 //!     ["jane", "doe", "999 anywhere"],
@@ -43,7 +52,7 @@
 //! * [`ShardedWriterBuilder::new_from_csv_reader`] -- The presence or absence of a header
 //!   is determined from a provided `csv::Reader` by way of its `.headers()` function.
 //!
-//! ```
+//! ```ignore
 //! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
 //!    .expect("Failed to create writer builder");
 //! ```
@@ -53,12 +62,91 @@
 //! example, if the first column (index 0) is the identifier, you can get the value or
 //! default to the empty string:
 //!
-//! ```
+//! ```ignore
 //! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
 //!    .expect("Failed to create writer builder");
 //!    .with_key_selector(|rec| rec.get(0).unwrap_or("unknown").to_owned());
 //! ```
 //!
+//! Rather than hard-coding column indices, you can shard on columns by name. The names are
+//! resolved against the header once, when the writer is built, failing with
+//! [`Error::MissingColumn`] if one is missing:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+//!    .key_by_columns(&["country", "state"], "/")?;
+//! ```
+//!
+//! To spread records evenly over a fixed number of files, use the built-in
+//! [`HashPartitioner`]. It uses a documented, unseeded hash, so every run on every machine
+//! places each row in the same bucket:
+//!
+//! ```ignore
+//! let builder = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?;
+//! let user_id = builder.column_index("user_id")?;
+//! let mut shard_writer = builder.with_selector(HashPartitioner::new(user_id, 64));
+//! ```
+//!
+//! Similarly, [`RangePartitioner`] buckets records by the numeric value of a column, given a
+//! sorted list of boundaries such as `[0.0, 100.0, 1000.0]`, and [`TimePartitioner`] buckets
+//! them by the year, month, day or hour of a timestamp column. Its keys display as hive-style
+//! directories:
+//!
+//! ```ignore
+//! let builder = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?;
+//! let event_time = builder.column_index("event_time")?;
+//! let mut shard_writer = builder
+//!     .with_selector(
+//!         TimePartitioner::new(event_time, "%Y-%m-%dT%H:%M:%S%z", Granularity::Hour)
+//!             .with_timezone(chrono::FixedOffset::west_opt(7 * 3600).unwrap()),
+//!     )
+//!     .with_output_shard_naming(|bucket, seq| format!("{bucket}/part-{seq}.csv"));
+//! // writes files like year=2026/month=10/day=16/hour=05/part-0.csv
+//! ```
+//!
+//! Keys needn't be strings: any `Hash + Eq + Clone + Display` type works, and the typed key is
+//! what the file naming closure and completion callback receive. For example, to shard on an
+//! integer customer ID without formatting it for every row:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
+//!    .expect("Failed to create writer builder")
+//!    .with_key_selector(|rec| rec.get(0).and_then(|id| id.parse::<u64>().ok()).unwrap_or(0))
+//!    .with_output_shard_naming(|id, seq| format!("customer{id:08}-{seq}.csv"));
+//! ```
+//!
+//! If a record may belong to several shards -- for example, when a column holds a list of
+//! tags -- use `.with_multi_key_selector` instead, returning every key for the record:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
+//!    .expect("Failed to create writer builder")
+//!    .with_multi_key_selector(|rec| {
+//!        rec.get(3).unwrap_or_default().split(';').map(str::to_owned).collect::<Vec<_>>()
+//!    });
+//! ```
+//!
+//! To drop some records entirely, use `.with_optional_key_selector` and return `None` for
+//! them; they are counted in [`ProcessSummary::records_filtered`]:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
+//!    .expect("Failed to create writer builder")
+//!    .with_optional_key_selector(|rec| rec.get(0).filter(|s| !s.is_empty()).map(str::to_owned));
+//! ```
+//!
+//! If selecting a key can fail, use `.with_try_key_selector`. By default, an error stops
+//! processing with [`Error::KeySelector`], which carries the failing record and its position:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
+//!    .expect("Failed to create writer builder")
+//!    .with_try_key_selector(|rec| {
+//!        let year: u16 = rec.get(4).unwrap_or_default().parse()?;
+//!        Ok::<_, std::num::ParseIntError>(year.to_string())
+//!    });
+//! ```
+//!
 //! Finally, specify how output shard files are named by passing a closure to
 //! `.with_output_shard_naming`. This closure is provided with the shard key and the
 //! zero-based sequence number of the shard. That is, the first file written for any
@@ -68,7 +156,7 @@
 //! Sequence numbers are generated based on [FileSplitting], which is specified by
 //! `.with_output_splitting`.
 //!
-//! ```
+//! ```ignore
 //! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
 //!    .expect("Failed to create writer builder");
 //!    .with_key_selector(|rec| rec.get(0).unwrap_or("unknown").to_owned());
@@ -78,53 +166,206 @@
 //! At this point, you can pass iterators of records (likely just the `csv_reader` itself)
 //! to the writer:
 //!
-//! ```
+//! ```ignore
 //! shard_writer.process_csv(&mut csv_reader).ok();
 //! ```
 //!
+//! Many input files of the same schema can be parsed concurrently with
+//! [`ShardedWriter::process_files_parallel`], which funnels their records into the same set of
+//! output shards:
+//!
+//! ```ignore
+//! shard_writer.process_files_parallel(&["2026-10-15.csv", "2026-10-16.csv"], 4)?;
+//! ```
+//!
+//! Files are flushed and closed when the writer is dropped, but any errors that occur while
+//! doing so are lost. To be sure every file was written completely, call
+//! [`ShardedWriter::finish`], which reports each file that failed to close:
+//!
+//! ```ignore
+//! let summary = shard_writer.finish()?;
+//! println!("Wrote {} records to {} files", summary.records_written, summary.files_written);
+//! ```
+//!
 //! # Additional options
+//! ## Malformed input rows
+//! By default, processing stops with [`Error::MalformedRow`] at the first input row that can't
+//! be parsed. Use `with_malformed_rows` to skip such rows instead, optionally writing them to
+//! a dead-letter file for later inspection. The dead-letter file records each row's line number
+//! and error along with the row exactly as it was read, so it can be fixed and processed again.
+//! Skipped rows are counted in the returned [`ProcessSummary`]:
+//!
+//! ```ignore
+//! shard_writer = shard_writer
+//!     .with_malformed_rows(MalformedRowPolicy::Quarantine("rejected.csv".into()));
+//! let summary = shard_writer.process_file("input.csv")?;
+//! println!("Skipped {} malformed rows", summary.records_skipped);
+//! ```
+//!
 //! ## Output Splitting
 //! By default, all rows for a given shard will be written to the same file. If you want
 //! to split the output into multiple files, provide details with `with_output_splitting`:
 //!
-//! ```
+//! ```ignore
 //! shard_writer = shard_writer.with_output_splitting(FileSplitting::SplitAfterRows(100));
 //! ```
 //!
+//! ## Input and output dialects
+//! Input and output files are comma-delimited with `csv`'s default quoting unless configured
+//! otherwise. `with_delimiter` sets the delimiter for both input and output, while
+//! `with_input_dialect` and `with_output_dialect` expose the underlying `csv::ReaderBuilder`
+//! and `csv::WriterBuilder` separately, so a single pass can convert formats while sharding:
+//!
+//! ```ignore
+//! shard_writer = shard_writer
+//!     .with_input_dialect(|b| b.delimiter(b'|'))
+//!     .with_output_dialect(|b| b.delimiter(b'\t').quote_style(csv::QuoteStyle::Never));
+//! ```
+//!
 //! ## File completion notification
 //! When a shard is done being written -- either becuase the specified number of rows or
 //! bytes were met and the writer is splitting to a new file or because the writer itself
 //! is being dropped and cleaning up open file handles -- you can be notified of the file's
-//! completion. The completed file path and the associated shard key will be provided.
+//! completion. A [`FileCompleted`] describing the file is provided, with its path, shard key,
+//! sequence number, the number of rows and bytes written and why it was completed, along with
+//! its SHA-256 checksum if `.with_checksums(true)` is set.
 //!
+//! ```ignore
+//! shard_writer = shard_writer.on_file_completion(|file| {
+//!     println!(
+//!         "Output file '{}' for key '{}' is complete with {} rows",
+//!         file.path.display(),
+//!         file.key,
+//!         file.rows
+//!     );
+//! });
 //! ```
-//! shard_writer = shard_writer.on_file_completion(|path, key| {
-//!     println!("Output file '{}' for key '{key}' is complete", path.display());
+//!
+//! The callback may capture its environment, so completed files can be handed off to another
+//! thread or an upload client:
+//!
+//! ```ignore
+//! let (tx, rx) = std::sync::mpsc::channel();
+//! shard_writer = shard_writer.on_file_completion(move |file| {
+//!     tx.send(file.path.to_owned()).ok();
 //! });
 //! ```
 //!
+//! Files appear under their final names as soon as they're created. If something watches for
+//! new files, use `.with_atomic_output(true)` so each file is written under a hidden temporary
+//! name and only renamed into place when it's complete, just before the callback is called.
+//! A writer that is dropped without calling `.finish` leaves its unfinished files under their
+//! temporary names.
+//!
+//! To keep a record of everything that was written, `.with_manifest` lists every completed
+//! file, with its key, sequence number, path, checksum and the number of rows and bytes
+//! written, in a CSV file written by `.finish`:
+//!
+//! ```ignore
+//! shard_writer = shard_writer.with_manifest("output/manifest.csv");
+//! ```
+//!
+//! ## Checkpoints
+//! A long run over large input files can save its progress every so many rows with
+//! `.with_checkpoint`. If the run is interrupted, calling `.resume_file` with the same inputs
+//! skips those that were finished, truncates the partially written output files back to the
+//! last checkpoint and carries on from there. When there's no checkpoint, `.resume_file`
+//! simply processes each whole file, so the same code both starts and resumes a run:
+//!
+//! ```ignore
+//! shard_writer = shard_writer.with_checkpoint("events.checkpoint", 1_000_000);
+//! for input in ["2026-10-15.csv", "2026-10-16.csv"] {
+//!     shard_writer.resume_file(input)?;
+//! }
+//! shard_writer.finish()?;
+//! ```
+//!
+//! Resuming reopens partially written output files for appending, so output written through
+//! `.on_create_file` must stay valid when appended to; gzipped output can't be resumed.
+//!
+//! Resuming recreates shard keys from their text with [`std::str::FromStr`], so it's available
+//! for keys such as `String`s, integers and [`TimeBucket`]s. The checkpoint also records every
+//! output file as it's created, so a resumed run rewrites only the files the interrupted run
+//! wrote; any other existing file is still handled according to the [`ExistingFilePolicy`].
+//!
+//! ## Limiting open files
+//! Each shard keeps its current output file open until it is split or the writer is dropped.
+//! When sharding on a high-cardinality column, this can exhaust the process's file handles.
+//! `with_max_open_files` caps the number of simultaneously open files; when the cap is hit,
+//! the least-recently-written file is closed and later reopened in append mode.
+//!
+//! ```ignore
+//! shard_writer = shard_writer.with_max_open_files(256);
+//! ```
+//!
+//! ## Output directories
+//! Output file names may include directories, such as the hive-style paths produced by
+//! [`TimePartitioner`]. Missing directories are created as each file is, and
+//! `.with_output_directory` puts every file under a common root:
+//!
+//! ```ignore
+//! let mut shard_writer = builder
+//!     .with_selector(TimePartitioner::new(event_time, "%Y-%m-%d %H:%M:%S", Granularity::Day))
+//!     .with_output_shard_naming(|bucket, seq| format!("{bucket}/part-{seq}.csv"))
+//!     .with_output_directory("/data/events");
+//! // writes files like /data/events/year=2026/month=10/day=16/part-0.csv
+//! ```
+//!
+//! ## Existing output files
+//! By default, an output file replaces any file already at its path. To protect the results of
+//! earlier runs, use `.with_existing_files` to fail instead, append to existing files or skip
+//! ahead to the next unused sequence number:
+//!
+//! ```ignore
+//! shard_writer = shard_writer.with_existing_files(ExistingFilePolicy::Error);
+//! ```
+//!
+//! ## Sanitizing keys
+//! Keys come from the data, so a key like `../../etc/x` would otherwise escape the output
+//! directory. Use `.with_sanitized_shard_naming` in place of `.with_output_shard_naming` to name
+//! files from keys made safe by a [`KeySanitizer`], which percent-encodes unsafe characters,
+//! rejects `.` and `..` and shortens long keys:
+//!
+//! ```ignore
+//! let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+//!    .key_by_column("customer")?
+//!    .with_sanitized_shard_naming(KeySanitizer::new(), |key, seq| format!("{key}-{seq}.csv"));
+//! ```
+//!
+//! Whichever way files are named, two files given the same path -- for example, for the keys
+//! `Foo` and `foo` on a case-insensitive filesystem -- fail with [`Error::PathCollision`]
+//! instead of one silently overwriting the other. See `.with_case_insensitive_paths`.
+//!
 //! ## Alternate file creation
-//! By default, when a new shard file is created, a `BufWriter<File>` is created
-//! automatically. If you want to create your own file (eg, with a GZip stream writer),
-//! override this with `.on_create_file`, which returns a `Box<dyn Write>` on success:
-//! ```
-//! shard_writer = shard_writer.on_create_file(|path| {
-//!     let f = std::fs::File::create(path)?;
-//!     let gz = flate2::write::GzEncoder::new(f, flate2::Compression::fast());
-//!     let buf = BufWriter::new(gz);
-//!     Ok(Box::new(buf))
+//! By default, when a new shard file is created, CSV data is written to a `BufWriter<File>`
+//! for it. If you want to write the file differently (eg, with a GZip stream writer), wrap that
+//! writer with `.on_create_file`, which returns a `Box<dyn Write + Send>` on success. The
+//! [`OpenMode`] indicates whether the file is new or has been reopened for appending:
+//! ```ignore
+//! shard_writer = shard_writer.on_create_file(|_path, _mode, file| {
+//!     let gz = flate2::write::GzEncoder::new(file, flate2::Compression::fast());
+//!     Ok(Box::new(gz))
 //! });
 //! ```
+mod input;
+mod key;
+mod naming;
+mod partition;
 mod shard;
 mod sharded_writer;
 
+pub use chrono;
 pub use csv;
+pub use key::*;
+pub use naming::*;
+pub use partition::*;
 pub use sharded_writer::*;
 
 /// Defines how output files will be split
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum FileSplitting {
     /// Output files won't be split
+    #[default]
     NoSplit,
 
     /// Output files will be split after at least some number of rows are written
@@ -134,9 +375,180 @@ pub enum FileSplitting {
     SplitAfterBytes(usize),
 }
 
-impl Default for FileSplitting {
-    fn default() -> Self {
-        FileSplitting::NoSplit
+/// Defines what happens to input rows that can't be parsed, eg, because they have a different
+/// number of fields than the header or aren't valid UTF-8.
+#[derive(Clone, Debug, Default)]
+pub enum MalformedRowPolicy {
+    /// Stop processing and return [`Error::MalformedRow`]
+    #[default]
+    FailFast,
+
+    /// Skip the row, counting it in [`ProcessSummary::records_skipped`]
+    Skip,
+
+    /// Skip the row as with [`MalformedRowPolicy::Skip`], and also write it to a dead-letter CSV
+    /// file at the given path. Each row of the dead-letter file has three fields: the input line
+    /// number, the error text, and the row's original bytes, less its line terminator.
+    ///
+    /// Rows from [`ShardedWriter::process_csv`], whose reader is built by the caller, and from
+    /// [`ShardedWriter::process_iter`] have no original bytes to keep, so their fields are
+    /// written out again as CSV with the default dialect instead.
+    Quarantine(std::path::PathBuf),
+}
+
+/// Defines what happens when an output file is about to be created at a path where a file
+/// already exists, eg, from a previous run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    /// Stop processing and return [`Error::FileExists`]
+    Error,
+
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+
+    /// Append to the existing file, writing the header only if the file is empty
+    Append,
+
+    /// Skip to the next sequence number whose file doesn't exist yet
+    NextSequence,
+}
+
+/// How an output file was opened, as given to the function passed to
+/// [`ShardedWriter::on_create_file`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// The file is being started; it was created, truncating anything already at the path.
+    Create,
+
+    /// The file was previously created and closed to limit the number of open files, or it
+    /// already existed and [`ExistingFilePolicy::Append`] is in use; it was opened so that
+    /// writes are appended to its end.
+    Append,
+}
+
+/// A summary of a single call to process input, such as [`ShardedWriter::process_file`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessSummary {
+    /// The number of valid input records read
+    pub records_read: usize,
+
+    /// The number of records written to output files. This is larger than `records_read` when
+    /// a [`MultiKey`] selector writes records to more than one shard.
+    pub records_written: usize,
+
+    /// The number of malformed rows and rows the key selector failed for that were skipped
+    /// according to the [`MalformedRowPolicy`]
+    pub records_skipped: usize,
+
+    /// The number of valid records that the key selector routed to no shard, eg, because an
+    /// [`OptionalKey`] selector returned `None`
+    pub records_filtered: usize,
+}
+
+impl std::ops::AddAssign for ProcessSummary {
+    fn add_assign(&mut self, rhs: Self) {
+        self.records_read += rhs.records_read;
+        self.records_written += rhs.records_written;
+        self.records_skipped += rhs.records_skipped;
+        self.records_filtered += rhs.records_filtered;
+    }
+}
+
+/// A summary of everything written by a [`ShardedWriter`], returned by
+/// [`ShardedWriter::finish`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// The number of valid input records read
+    pub records_read: usize,
+
+    /// The number of records written across all shards
+    pub records_written: usize,
+
+    /// The number of malformed rows skipped according to the [`MalformedRowPolicy`]
+    pub records_skipped: usize,
+
+    /// The number of valid records that the key selector routed to no shard
+    pub records_filtered: usize,
+
+    /// The number of distinct shard keys seen
+    pub shards: usize,
+
+    /// The number of output files written across all shards
+    pub files_written: usize,
+}
+
+/// Why an output file was completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionReason {
+    /// The file reached the size given by [FileSplitting], and the shard moved on to a new file
+    Split,
+
+    /// The writer was finished or dropped
+    Finished,
+}
+
+/// Describes a completed output file, as given to the callback passed to
+/// [`ShardedWriter::on_file_completion`].
+#[derive(Clone, Copy, Debug)]
+pub struct FileCompleted<'a, K> {
+    /// The file's final path
+    pub path: &'a std::path::Path,
+
+    /// The key of the shard the file belongs to
+    pub key: &'a K,
+
+    /// The file's zero-based sequence number within its shard
+    pub sequence: usize,
+
+    /// The number of records written to the file, not counting the header
+    pub rows: usize,
+
+    /// The size of the file in bytes as stored, after any compression added by
+    /// [`ShardedWriter::on_create_file`]. When appending to an existing file, this includes
+    /// what was already in it.
+    pub bytes: u64,
+
+    /// The SHA-256 checksum of the file in lowercase hex, if [`ShardedWriter::with_checksums`]
+    /// is enabled
+    pub checksum: Option<&'a str>,
+
+    /// Why the file was completed
+    pub reason: CompletionReason,
+
+    /// Where the first record written to the file was found in its input, if it was read by a
+    /// [`csv::Reader`]
+    pub first_record: Option<&'a csv::Position>,
+
+    /// Where the last record written to the file was found in its input, if it was read by a
+    /// [`csv::Reader`]. With [`ShardedWriter::process_files_parallel`], this may be in a
+    /// different input file than the first record.
+    pub last_record: Option<&'a csv::Position>,
+}
+
+/// An I/O error encountered while flushing or closing a single output file.
+#[derive(Debug)]
+pub struct FileError {
+    /// The path of the file that failed
+    pub path: std::path::PathBuf,
+
+    /// The shard key of the file that failed, or the empty string for the dead-letter file of
+    /// [`MalformedRowPolicy::Quarantine`]
+    pub key: String,
+
+    /// The underlying error
+    pub error: std::io::Error,
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to close '{}' for shard '{}': {}",
+            self.path.display(),
+            self.key,
+            self.error
+        )
     }
 }
 
@@ -144,6 +556,144 @@ impl Default for FileSplitting {
 pub enum Error {
     Csv(csv::Error),
     IO(std::io::Error),
+
+    /// One or more output files couldn't be flushed and closed
+    Close(Vec<FileError>),
+
+    /// An input row couldn't be parsed and the [`MalformedRowPolicy`] is to fail fast
+    MalformedRow {
+        /// The one-based line on which the row started
+        line: u64,
+
+        /// Why the row couldn't be parsed
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A column named when building the writer isn't in the header, or there is no header
+    MissingColumn(String),
+
+    /// A shard key can't be made into a safe file name by the [`KeySanitizer`]
+    UnsafeKey(String),
+
+    /// An output file already exists and the [`ExistingFilePolicy`] is to fail
+    FileExists(std::path::PathBuf),
+
+    /// A checkpoint couldn't be resumed from, eg, because it's for a different input file
+    Checkpoint(String),
+
+    /// Two different shard files were named with the same path, so the second would overwrite
+    /// the first
+    PathCollision {
+        /// The path both files were given
+        path: std::path::PathBuf,
+
+        /// The key of the file being created, as displayed
+        key: String,
+
+        /// The sequence number of the file being created
+        sequence: usize,
+
+        /// The key of the file that already had the path, as displayed
+        other_key: String,
+
+        /// The sequence number of the file that already had the path
+        other_sequence: usize,
+    },
+
+    /// The key selector failed for a record and the [`MalformedRowPolicy`] is to fail fast
+    KeySelector {
+        /// The error returned by the key selector
+        error: KeyError,
+
+        /// The zero-based index of the row among every row the writer has processed, counting
+        /// across calls and from the start of a run resumed with
+        /// [`ShardedWriter::resume_file`]. Rows from [`ShardedWriter::process_files_parallel`]
+        /// are counted in the order they're written, which varies between runs; use `position`
+        /// to find a row in its input file.
+        index: usize,
+
+        /// Where the record was found in its input, if it was read by a [`csv::Reader`]
+        position: Option<csv::Position>,
+
+        /// The record the key selector failed for
+        record: csv::StringRecord,
+    },
+
+    /// An input file given to [`ShardedWriter::process_files_parallel`] couldn't be read, or one
+    /// of its rows failed
+    Input {
+        /// The input file
+        path: std::path::PathBuf,
+
+        /// What went wrong
+        source: Box<Error>,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Csv(e) => e.fmt(f),
+            Error::IO(e) => e.fmt(f),
+            Error::Close(errors) => {
+                write!(f, "{} output file(s) failed to close", errors.len())?;
+                for e in errors {
+                    write!(f, "; {e}")?;
+                }
+                Ok(())
+            }
+            Error::MalformedRow { line, error } => {
+                write!(f, "malformed row on line {line}: {error}")
+            }
+            Error::MissingColumn(name) => write!(f, "column '{name}' not found in the header"),
+            Error::UnsafeKey(key) => write!(f, "shard key '{key}' can't be used in a file name"),
+            Error::FileExists(path) => write!(f, "output file {} already exists", path.display()),
+            Error::Checkpoint(reason) => write!(f, "can't resume from checkpoint: {reason}"),
+            Error::PathCollision {
+                path,
+                key,
+                sequence,
+                other_key,
+                other_sequence,
+            } => write!(
+                f,
+                "file {sequence} of shard '{key}' and file {other_sequence} of shard '{other_key}' are both named {}",
+                path.display()
+            ),
+            Error::KeySelector {
+                error,
+                index,
+                position,
+                ..
+            } => match position {
+                Some(p) => write!(
+                    f,
+                    "key selector failed for row on line {}: {error}",
+                    p.line()
+                ),
+                None => write!(f, "key selector failed for row {index}: {error}"),
+            },
+            Error::Input { path, source } => write!(f, "input file {}: {source}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Csv(e) => Some(e),
+            Error::IO(e) => Some(e),
+            Error::Close(errors) => errors.first().map(|e| &e.error as _),
+            Error::MalformedRow { error, .. } => Some(error.as_ref()),
+            Error::MissingColumn(_)
+            | Error::UnsafeKey(_)
+            | Error::FileExists(_)
+            | Error::Checkpoint(_)
+            | Error::PathCollision { .. } => None,
+            Error::KeySelector { error, .. } => Some(error.as_ref()),
+            Error::Input { source, .. } => Some(source.as_ref()),
+        }
+    }
 }
 
 impl From<csv::Error> for Error {
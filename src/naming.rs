@@ -0,0 +1,201 @@
+use crate::{partition::fnv1a_64, Error};
+use std::fmt::Display;
+
+/// Determines the name of each output file from its shard key and sequence number.
+///
+/// Any closure of the form `Fn(&K, usize) -> String` names files, as passed to
+/// [`crate::ShardedWriterWithKey::with_output_shard_naming`]. [Sanitized] wraps a closure so
+/// that it is given a [KeySanitizer]'s file-safe version of the key instead.
+pub trait ShardNaming<K> {
+    /// Names the file with the zero-based `sequence` number for the shard with `key`.
+    fn file_name(&self, key: &K, sequence: usize) -> Result<String, Error>;
+}
+
+impl<F, K> ShardNaming<K> for F
+where
+    F: Fn(&K, usize) -> String,
+{
+    fn file_name(&self, key: &K, sequence: usize) -> Result<String, Error> {
+        Ok(self(key, sequence))
+    }
+}
+
+/// Makes shard keys safe to use in file names.
+///
+/// Keys come straight from the data, so without sanitizing, a key like `../../etc/x` or `a/b`
+/// would write outside the output directory, and keys containing `:` or hundreds of characters
+/// would fail to be created on some filesystems. A sanitizer:
+/// * Percent-encodes path separators, control characters, `% : * ? " < > |` and any extra
+///   characters given to [`KeySanitizer::with_escaped_chars`], so `a/b` becomes `a%2Fb`;
+/// * Rejects keys that are empty, `.` or `..` with [`Error::UnsafeKey`];
+/// * Truncates keys longer than the maximum length (128 bytes by default) once encoded,
+///   replacing the end with a hash of the whole key so distinct long keys stay distinct.
+///
+/// ```
+/// # use shard_csv::KeySanitizer;
+/// let sanitizer = KeySanitizer::new().with_max_length(64);
+/// assert_eq!(sanitizer.sanitize("a/b:c")?, "a%2Fb%3Ac");
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct KeySanitizer {
+    escaped_chars: String,
+    max_length: usize,
+}
+
+impl Default for KeySanitizer {
+    fn default() -> Self {
+        Self {
+            escaped_chars: String::new(),
+            max_length: 128,
+        }
+    }
+}
+
+impl KeySanitizer {
+    /// The number of bytes a truncated key's hash suffix takes up, including its separator.
+    const HASH_SUFFIX_LEN: usize = 17;
+
+    /// Creates a sanitizer with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also percent-encodes each of `chars`, eg, `" "` to keep spaces out of file names.
+    pub fn with_escaped_chars(mut self, chars: &str) -> Self {
+        self.escaped_chars.push_str(chars);
+        self
+    }
+
+    /// Sets the maximum length in bytes of a sanitized key. Longer keys are truncated with a
+    /// hash suffix, so the length can't be less than 32.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length.max(32);
+        self
+    }
+
+    /// Sanitizes `key` for use in a file name.
+    pub fn sanitize(&self, key: &str) -> Result<String, Error> {
+        if matches!(key, "" | "." | "..") {
+            return Err(Error::UnsafeKey(key.to_owned()));
+        }
+
+        let mut sanitized = String::with_capacity(key.len());
+        for c in key.chars() {
+            if self.is_escaped(c) {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    sanitized.push_str(&format!("%{b:02X}"));
+                }
+            } else {
+                sanitized.push(c);
+            }
+        }
+
+        if sanitized.len() > self.max_length {
+            let mut end = self.max_length - Self::HASH_SUFFIX_LEN;
+            while !sanitized.is_char_boundary(end) {
+                end -= 1;
+            }
+            // Don't leave half of a percent-encoded byte behind.
+            if let Some(percent) = sanitized[..end].rfind('%') {
+                if percent + 3 > end {
+                    end = percent;
+                }
+            }
+
+            sanitized.truncate(end);
+            sanitized.push_str(&format!("-{:016x}", fnv1a_64(key.as_bytes())));
+        }
+
+        Ok(sanitized)
+    }
+
+    fn is_escaped(&self, c: char) -> bool {
+        c.is_control() || "/\\%:*?\"<>|".contains(c) || self.escaped_chars.contains(c)
+    }
+}
+
+/// A [ShardNaming] that passes the closure a sanitized version of each key's [Display] form.
+///
+/// This is usually created with
+/// [`crate::ShardedWriterWithKey::with_sanitized_shard_naming`]. Only the file name sees the
+/// sanitized key; completion callbacks still receive the original.
+pub struct Sanitized<F> {
+    /// The sanitizer applied to each key
+    pub sanitizer: KeySanitizer,
+
+    /// Names files from the sanitized key and sequence number
+    pub naming: F,
+}
+
+impl<F, K> ShardNaming<K> for Sanitized<F>
+where
+    F: Fn(&str, usize) -> String,
+    K: Display,
+{
+    fn file_name(&self, key: &K, sequence: usize) -> Result<String, Error> {
+        let key = self.sanitizer.sanitize(&key.to_string())?;
+        Ok((self.naming)(&key, sequence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_escapes_unsafe_characters() {
+        let sanitizer = KeySanitizer::new();
+        assert_eq!(sanitizer.sanitize("plain-key_1").unwrap(), "plain-key_1");
+        assert_eq!(
+            sanitizer.sanitize("../../etc/x").unwrap(),
+            "..%2F..%2Fetc%2Fx"
+        );
+        assert_eq!(sanitizer.sanitize("a\\b:c*d").unwrap(), "a%5Cb%3Ac%2Ad");
+        assert_eq!(sanitizer.sanitize("100%").unwrap(), "100%25");
+        assert_eq!(sanitizer.sanitize("tab\there").unwrap(), "tab%09here");
+        assert_eq!(sanitizer.sanitize("café").unwrap(), "café");
+
+        let sanitizer = sanitizer.with_escaped_chars(" é");
+        assert_eq!(sanitizer.sanitize("a café").unwrap(), "a%20caf%C3%A9");
+    }
+
+    #[test]
+    fn sanitize_rejects_relative_keys() {
+        for key in ["", ".", ".."] {
+            assert!(matches!(
+                KeySanitizer::new().sanitize(key),
+                Err(Error::UnsafeKey(k)) if k == key
+            ));
+        }
+    }
+
+    #[test]
+    fn sanitize_truncates_long_keys_with_a_hash() {
+        let sanitizer = KeySanitizer::new().with_max_length(32);
+        let long = "k".repeat(40);
+        let sanitized = sanitizer.sanitize(&long).unwrap();
+        assert_eq!(
+            sanitized,
+            format!("{}-{:016x}", "k".repeat(15), fnv1a_64(long.as_bytes()))
+        );
+
+        // Distinct keys with the same prefix stay distinct.
+        let other = sanitizer.sanitize(&format!("{long}x")).unwrap();
+        assert_ne!(sanitized, other);
+        assert_eq!(other.len(), 32);
+
+        // A percent-encoded byte isn't split by truncation.
+        let slashes = sanitizer
+            .sanitize(&format!("{}/{}", "k".repeat(14), "k".repeat(20)))
+            .unwrap();
+        assert!(slashes.starts_with(&format!("{}-", "k".repeat(14))));
+        assert!(slashes.len() <= 32);
+
+        // Truncation doesn't split a multi-byte character.
+        let accents = sanitizer.sanitize(&"é".repeat(20)).unwrap();
+        assert!(accents.starts_with(&"é".repeat(7)));
+        assert!(accents.len() <= 32);
+    }
+}
@@ -0,0 +1,581 @@
+use crate::key::{field, KeyError, KeySelector};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use csv::StringRecord;
+use std::fmt::Display;
+
+/// The 64-bit FNV-1a hash of `bytes`, as used by [HashPartitioner].
+///
+/// Unlike [std::collections::hash_map::DefaultHasher], this is unseeded and fully specified,
+/// so the same input hashes to the same value on every machine, in every run and in any
+/// language with an FNV-1a implementation.
+pub fn fnv1a_64(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(PRIME)
+    })
+}
+
+/// A key selector that spreads records evenly across a fixed number of buckets by hashing one
+/// or more columns.
+///
+/// The bucket is the [fnv1a_64] hash of the column's UTF-8 bytes modulo the number of buckets,
+/// so two runs on different machines place every row in the same bucket. When several columns
+/// are hashed, each field's bytes are followed by a `0xFF` byte, which can't occur in UTF-8, so
+/// `("ab", "c")` and `("a", "bc")` hash differently.
+///
+/// Keys are the bucket numbers zero-padded to the width of the largest bucket, eg, `"00"` to
+/// `"63"` for 64 buckets, so shard files sort in bucket order.
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("user_id,name\n".as_bytes());
+/// let builder = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?;
+/// let user_id = builder.column_index("user_id")?;
+/// let writer = builder
+///     .with_selector(HashPartitioner::new(user_id, 64))
+///     .with_output_shard_naming(|bucket, seq| format!("bucket={bucket}/part-{seq}.csv"));
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct HashPartitioner {
+    columns: Vec<usize>,
+    buckets: u64,
+    width: usize,
+}
+
+impl HashPartitioner {
+    /// Creates a partitioner hashing the zero-based `column` into `buckets` buckets.
+    ///
+    /// # Panics
+    /// Panics if `buckets` is zero.
+    pub fn new(column: usize, buckets: u64) -> Self {
+        Self::with_columns(vec![column], buckets)
+    }
+
+    /// Creates a partitioner hashing the zero-based `columns` together into `buckets` buckets.
+    ///
+    /// # Panics
+    /// Panics if `buckets` is zero.
+    pub fn with_columns(columns: Vec<usize>, buckets: u64) -> Self {
+        assert!(buckets > 0, "a HashPartitioner needs at least one bucket");
+
+        Self {
+            columns,
+            buckets,
+            width: (buckets - 1).to_string().len(),
+        }
+    }
+
+    /// Finds the bucket for `record`, failing if the record is missing a hashed column.
+    pub fn bucket(&self, record: &StringRecord) -> Result<u64, KeyError> {
+        let hash = match self.columns.as_slice() {
+            [column] => fnv1a_64(field(record, *column)?.as_bytes()),
+            columns => {
+                let mut bytes = Vec::new();
+                for &column in columns {
+                    bytes.extend_from_slice(field(record, column)?.as_bytes());
+                    bytes.push(0xFF);
+                }
+                fnv1a_64(&bytes)
+            }
+        };
+
+        Ok(hash % self.buckets)
+    }
+}
+
+impl KeySelector for HashPartitioner {
+    type Key = String;
+
+    fn select_keys(&self, record: &StringRecord, keys: &mut Vec<String>) -> Result<(), KeyError> {
+        let bucket = self.bucket(record)?;
+        keys.push(format!("{bucket:0width$}", width = self.width));
+        Ok(())
+    }
+}
+
+/// What a [RangePartitioner] does with values that aren't numbers.
+#[derive(Clone, Debug, Default)]
+pub enum UnparsableValue {
+    /// Fail to select a key, so the row is handled according to the writer's
+    /// [`crate::MalformedRowPolicy`]
+    #[default]
+    Malformed,
+
+    /// Route the row to the shard with this key
+    Shard(String),
+}
+
+/// A key selector that buckets records by the numeric value of a column, given a sorted list
+/// of boundaries.
+///
+/// With `n` boundaries there are `n + 1` buckets: values below the first boundary, values from
+/// each boundary up to (but not including) the next, and values at or above the last boundary.
+/// By default, buckets are labelled from the boundaries, so boundaries of `[0, 100, 1000]`
+/// give the keys `"<0"`, `"0-100"`, `"100-1000"` and `">=1000"`; use
+/// [`RangePartitioner::with_labels`] to name them yourself.
+///
+/// Values are parsed as `f64` after trimming whitespace, which handles both integers and
+/// floats. Values that don't parse, including `NaN`, are handled according to the
+/// [UnparsableValue] setting.
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("id,amount\n".as_bytes());
+/// let builder = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?;
+/// let amount = builder.column_index("amount")?;
+/// let writer = builder
+///     .with_selector(
+///         RangePartitioner::new(amount, vec![0.0, 100.0, 1000.0])
+///             .on_unparsable(UnparsableValue::Shard("invalid".to_owned())),
+///     )
+///     .with_output_shard_naming(|tier, seq| format!("amount{tier}-{seq}.csv"));
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct RangePartitioner {
+    column: usize,
+    boundaries: Vec<f64>,
+    labels: Vec<String>,
+    unparsable: UnparsableValue,
+}
+
+impl RangePartitioner {
+    /// Creates a partitioner bucketing the zero-based `column` by `boundaries`.
+    ///
+    /// # Panics
+    /// Panics if `boundaries` is empty, contains `NaN`, or isn't sorted in strictly increasing
+    /// order.
+    pub fn new(column: usize, boundaries: Vec<f64>) -> Self {
+        assert!(
+            !boundaries.is_empty(),
+            "a RangePartitioner needs at least one boundary"
+        );
+        assert!(
+            boundaries.windows(2).all(|w| w[0] < w[1]) && !boundaries.iter().any(|b| b.is_nan()),
+            "RangePartitioner boundaries must be sorted in strictly increasing order"
+        );
+
+        let mut labels = Vec::with_capacity(boundaries.len() + 1);
+        labels.push(format!("<{}", boundaries[0]));
+        labels.extend(boundaries.windows(2).map(|w| format!("{}-{}", w[0], w[1])));
+        labels.push(format!(">={}", boundaries[boundaries.len() - 1]));
+
+        Self {
+            column,
+            boundaries,
+            labels,
+            unparsable: UnparsableValue::Malformed,
+        }
+    }
+
+    /// Replaces the default bucket labels, from the lowest bucket to the highest.
+    ///
+    /// # Panics
+    /// Panics unless there is exactly one more label than there are boundaries.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        assert_eq!(
+            labels.len(),
+            self.boundaries.len() + 1,
+            "a RangePartitioner needs one more label than it has boundaries"
+        );
+
+        self.labels = labels;
+        self
+    }
+
+    /// Specifies what happens to values that aren't numbers. The default is
+    /// [`UnparsableValue::Malformed`].
+    pub fn on_unparsable(mut self, unparsable: UnparsableValue) -> Self {
+        self.unparsable = unparsable;
+        self
+    }
+
+    /// Finds the label of the bucket that `value` falls in.
+    pub fn label(&self, value: f64) -> &str {
+        let bucket = self.boundaries.partition_point(|&b| b <= value);
+        &self.labels[bucket]
+    }
+}
+
+impl KeySelector for RangePartitioner {
+    type Key = String;
+
+    fn select_keys(&self, record: &StringRecord, keys: &mut Vec<String>) -> Result<(), KeyError> {
+        let value = field(record, self.column)?;
+        let key = match value.trim().parse::<f64>() {
+            Ok(number) if !number.is_nan() => self.label(number).to_owned(),
+            _ => match &self.unparsable {
+                UnparsableValue::Shard(key) => key.clone(),
+                UnparsableValue::Malformed => {
+                    return Err(
+                        format!("'{value}' in column {} isn't a number", self.column).into(),
+                    )
+                }
+            },
+        };
+
+        keys.push(key);
+        Ok(())
+    }
+}
+
+/// How finely a [TimePartitioner] buckets timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Granularity {
+    /// One bucket per calendar year
+    Year,
+    /// One bucket per calendar month
+    Month,
+    /// One bucket per calendar day
+    Day,
+    /// One bucket per hour
+    Hour,
+}
+
+/// The key selected by a [TimePartitioner]: the period a timestamp falls in.
+///
+/// Fields finer than the partitioner's [Granularity] are `None`. This displays as a hive-style
+/// path such as `year=2026/month=10/day=16`, with every field but the year zero-padded to two
+/// digits, so it can be used directly as a directory in the output file name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimeBucket {
+    /// The calendar year
+    pub year: i32,
+    /// The month, from 1 to 12
+    pub month: Option<u32>,
+    /// The day of the month, from 1 to 31
+    pub day: Option<u32>,
+    /// The hour, from 0 to 23
+    pub hour: Option<u32>,
+}
+
+impl TimeBucket {
+    fn new<Tz: TimeZone>(time: &DateTime<Tz>, granularity: Granularity) -> Self {
+        Self {
+            year: time.year(),
+            month: (granularity >= Granularity::Month).then(|| time.month()),
+            day: (granularity >= Granularity::Day).then(|| time.day()),
+            hour: (granularity >= Granularity::Hour).then(|| time.hour()),
+        }
+    }
+}
+
+impl Display for TimeBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "year={}", self.year)?;
+        for (name, value) in [
+            ("month", self.month),
+            ("day", self.day),
+            ("hour", self.hour),
+        ] {
+            if let Some(value) = value {
+                write!(f, "/{name}={value:02}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the hive-style [Display] form of a bucket, such as `year=2026/month=10/day=16`.
+impl std::str::FromStr for TimeBucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("bad time bucket '{s}'");
+        let mut fields = [None; 4];
+        for (i, part) in s.split('/').enumerate() {
+            let name = ["year", "month", "day", "hour"].get(i).ok_or_else(bad)?;
+            fields[i] = match part.split_once('=') {
+                Some((n, value)) if n == *name => Some(value.parse::<i32>().map_err(|_| bad())?),
+                _ => return Err(bad()),
+            };
+        }
+
+        let [year, month, day, hour] = fields;
+        let unsigned = |value: Option<i32>| value.map(|v| u32::try_from(v).map_err(|_| bad()));
+        Ok(Self {
+            year: year.ok_or_else(bad)?,
+            month: unsigned(month).transpose()?,
+            day: unsigned(day).transpose()?,
+            hour: unsigned(hour).transpose()?,
+        })
+    }
+}
+
+/// A key selector that buckets records by the time in a timestamp column.
+///
+/// Timestamps are parsed with a [`chrono` format string](chrono::format::strftime). If the
+/// format includes an offset (eg, `%z`), the timestamp is converted to the partitioner's time
+/// zone; otherwise it's taken to already be in that time zone. A format with no time of day,
+/// such as `%Y-%m-%d`, is read as midnight. The time zone is UTC unless one is given with
+/// [`TimePartitioner::with_timezone`], which takes any [chrono::TimeZone], such as a
+/// [chrono::FixedOffset] or a `chrono_tz::Tz`.
+///
+/// Keys are [TimeBucket]s, which display as hive-style directories. Timestamps that don't parse
+/// are key selector errors, handled according to the writer's [`crate::MalformedRowPolicy`].
+///
+/// ```
+/// # use shard_csv::*;
+/// # let mut csv_reader = csv::Reader::from_reader("id,event_time\n".as_bytes());
+/// let builder = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?;
+/// let event_time = builder.column_index("event_time")?;
+/// let writer = builder
+///     .with_selector(TimePartitioner::new(event_time, "%Y-%m-%d %H:%M:%S", Granularity::Day))
+///     .with_output_shard_naming(|bucket, seq| format!("{bucket}/part-{seq}.csv"));
+/// // writes files like year=2026/month=10/day=16/part-0.csv
+/// # Ok::<(), shard_csv::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct TimePartitioner<Tz: TimeZone = Utc> {
+    column: usize,
+    format: String,
+    granularity: Granularity,
+    timezone: Tz,
+}
+
+impl TimePartitioner {
+    /// Creates a partitioner parsing the zero-based `column` with `format` and bucketing it by
+    /// `granularity` in UTC.
+    pub fn new(column: usize, format: &str, granularity: Granularity) -> Self {
+        Self {
+            column,
+            format: format.to_owned(),
+            granularity,
+            timezone: Utc,
+        }
+    }
+}
+
+impl<Tz: TimeZone> TimePartitioner<Tz> {
+    /// Buckets timestamps in `timezone` rather than UTC.
+    pub fn with_timezone<Tz2: TimeZone>(self, timezone: Tz2) -> TimePartitioner<Tz2> {
+        TimePartitioner {
+            column: self.column,
+            format: self.format,
+            granularity: self.granularity,
+            timezone,
+        }
+    }
+
+    /// Parses `value` with the partitioner's format into its time zone.
+    pub fn parse(&self, value: &str) -> Result<DateTime<Tz>, KeyError> {
+        let value = value.trim();
+        if let Ok(time) = DateTime::parse_from_str(value, &self.format) {
+            return Ok(time.with_timezone(&self.timezone));
+        }
+
+        let naive = match NaiveDateTime::parse_from_str(value, &self.format) {
+            Ok(naive) => naive,
+            Err(e) => match NaiveDate::parse_from_str(value, &self.format) {
+                Ok(date) => date.and_time(chrono::NaiveTime::MIN),
+                Err(_) => return Err(format!("can't parse '{value}' as a timestamp: {e}").into()),
+            },
+        };
+
+        self.timezone
+            .from_local_datetime(&naive)
+            .earliest()
+            .ok_or_else(|| format!("'{value}' doesn't exist in the time zone").into())
+    }
+}
+
+impl<Tz: TimeZone> KeySelector for TimePartitioner<Tz> {
+    type Key = TimeBucket;
+
+    fn select_keys(
+        &self,
+        record: &StringRecord,
+        keys: &mut Vec<TimeBucket>,
+    ) -> Result<(), KeyError> {
+        let time = self.parse(field(record, self.column)?)?;
+        keys.push(TimeBucket::new(&time, self.granularity));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    #[test]
+    fn fnv1a_64_matches_reference_vectors() {
+        assert_eq!(fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a_64(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn hash_partitioner_pads_buckets() {
+        let partitioner = HashPartitioner::new(0, 64);
+        let record = StringRecord::from(vec!["foobar"]);
+        assert_eq!(
+            partitioner.bucket(&record).unwrap(),
+            0x8594_4171_f739_67e8 % 64
+        );
+
+        let mut keys = Vec::new();
+        partitioner.select_keys(&record, &mut keys).unwrap();
+        assert_eq!(keys, [format!("{:02}", 0x8594_4171_f739_67e8_u64 % 64)]);
+    }
+
+    #[test]
+    fn range_partitioner_labels_buckets() {
+        let partitioner = RangePartitioner::new(0, vec![0.0, 100.0, 1000.0]);
+        assert_eq!(partitioner.label(-0.5), "<0");
+        assert_eq!(partitioner.label(0.0), "0-100");
+        assert_eq!(partitioner.label(99.9), "0-100");
+        assert_eq!(partitioner.label(100.0), "100-1000");
+        assert_eq!(partitioner.label(1000.0), ">=1000");
+        assert_eq!(partitioner.label(f64::INFINITY), ">=1000");
+
+        let labels = ["low", "mid", "high", "top"].map(str::to_owned).to_vec();
+        let partitioner = partitioner.with_labels(labels);
+        assert_eq!(partitioner.label(f64::NEG_INFINITY), "low");
+        assert_eq!(partitioner.label(500.0), "high");
+    }
+
+    #[test]
+    fn range_partitioner_handles_unparsable_values() {
+        let mut keys = Vec::new();
+        let record = StringRecord::from(vec!["NaN"]);
+        assert!(RangePartitioner::new(0, vec![0.0])
+            .select_keys(&record, &mut keys)
+            .is_err());
+
+        RangePartitioner::new(0, vec![0.0])
+            .on_unparsable(UnparsableValue::Shard("invalid".to_owned()))
+            .select_keys(&record, &mut keys)
+            .unwrap();
+        assert_eq!(keys, ["invalid"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn range_partitioner_rejects_unsorted_boundaries() {
+        RangePartitioner::new(0, vec![100.0, 0.0]);
+    }
+
+    #[test]
+    fn time_bucket_round_trips_through_its_display_form() {
+        let bucket = TimeBucket {
+            year: 2026,
+            month: Some(10),
+            day: Some(6),
+            hour: None,
+        };
+        assert_eq!(bucket.to_string(), "year=2026/month=10/day=06");
+        assert_eq!("year=2026/month=10/day=06".parse(), Ok(bucket));
+
+        let year = TimeBucket {
+            year: 2026,
+            month: None,
+            day: None,
+            hour: None,
+        };
+        assert_eq!("year=2026".parse(), Ok(year));
+    }
+
+    #[test]
+    fn time_bucket_rejects_malformed_text() {
+        for text in [
+            "",
+            "2026",
+            "month=10",
+            "year=2026/day=16",
+            "year=2026/month=10/day=16/hour=05/minute=00",
+            "year=2026/month=-1",
+            "year=twenty",
+        ] {
+            assert!(text.parse::<TimeBucket>().is_err(), "parsed '{text}'");
+        }
+    }
+
+    #[test]
+    fn time_partitioner_converts_offsets_into_its_time_zone() {
+        let utc = TimePartitioner::new(0, "%Y-%m-%dT%H:%M:%S%z", Granularity::Day);
+        let time = utc.parse("2026-10-16T23:30:00+0200").unwrap();
+        assert_eq!(time, Utc.with_ymd_and_hms(2026, 10, 16, 21, 30, 0).unwrap());
+
+        // Five hours east of UTC, the same moment is on the next day.
+        let east = FixedOffset::east_opt(5 * 3600).unwrap();
+        let partitioner = utc.with_timezone(east);
+        let time = partitioner.parse("2026-10-16T23:30:00+0200").unwrap();
+        assert_eq!(time.to_rfc3339(), "2026-10-17T02:30:00+05:00");
+
+        let mut keys = Vec::new();
+        let record = StringRecord::from(vec!["2026-10-16T23:30:00+0200"]);
+        partitioner.select_keys(&record, &mut keys).unwrap();
+        assert_eq!(keys[0].to_string(), "year=2026/month=10/day=17");
+    }
+
+    #[test]
+    fn time_partitioner_reads_dates_as_midnight() {
+        let partitioner = TimePartitioner::new(0, "%Y-%m-%d", Granularity::Hour);
+        let time = partitioner.parse(" 2026-10-16 ").unwrap();
+        assert_eq!(time, Utc.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap());
+
+        let west = FixedOffset::west_opt(7 * 3600).unwrap();
+        let time = partitioner.with_timezone(west).parse("2026-10-16").unwrap();
+        assert_eq!(time.to_rfc3339(), "2026-10-16T00:00:00-07:00");
+    }
+
+    /// UTC, except that the hour from 02:00 on 2026-03-29 is skipped, as it would be when the
+    /// clocks go forward.
+    #[derive(Clone, Debug)]
+    struct SpringForward;
+
+    impl TimeZone for SpringForward {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            SpringForward
+        }
+
+        fn offset_from_local_date(&self, _: &NaiveDate) -> chrono::LocalResult<FixedOffset> {
+            chrono::LocalResult::Single(FixedOffset::east_opt(0).unwrap())
+        }
+
+        fn offset_from_local_datetime(
+            &self,
+            local: &NaiveDateTime,
+        ) -> chrono::LocalResult<FixedOffset> {
+            let skipped = NaiveDate::from_ymd_opt(2026, 3, 29).unwrap();
+            if local.date() == skipped && local.hour() == 2 {
+                chrono::LocalResult::None
+            } else {
+                chrono::LocalResult::Single(FixedOffset::east_opt(0).unwrap())
+            }
+        }
+
+        fn offset_from_utc_date(&self, _: &NaiveDate) -> FixedOffset {
+            FixedOffset::east_opt(0).unwrap()
+        }
+
+        fn offset_from_utc_datetime(&self, _: &NaiveDateTime) -> FixedOffset {
+            FixedOffset::east_opt(0).unwrap()
+        }
+    }
+
+    #[test]
+    fn time_partitioner_rejects_bad_timestamps() {
+        let partitioner = TimePartitioner::new(0, "%Y-%m-%d %H:%M:%S", Granularity::Hour);
+        for value in [
+            "",
+            "yesterday",
+            "2026-10-16T12:00:00",
+            "2026-02-30 12:00:00",
+        ] {
+            let error = partitioner.parse(value).unwrap_err().to_string();
+            assert!(error.starts_with("can't parse"), "{value}: {error}");
+        }
+
+        let partitioner = partitioner.with_timezone(SpringForward);
+        let error = partitioner.parse("2026-03-29 02:30:00").unwrap_err();
+        assert!(error.to_string().contains("doesn't exist"), "{error}");
+        assert!(partitioner.parse("2026-03-29 03:30:00").is_ok());
+    }
+}
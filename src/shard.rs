@@ -1,29 +1,377 @@
-use crate::{Error, FileSplitting};
+use crate::{
+    CompletionReason, Error, ExistingFilePolicy, FileCompleted, FileSplitting, OpenMode,
+    ShardNaming,
+};
 use csv::{StringRecord, Writer};
+use sha2::{Digest, Sha256};
 use std::{
-    io::Write,
+    collections::{HashMap, HashSet},
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
-    rc::Rc,
+    sync::{Arc, Mutex, PoisonError},
 };
 
-pub(crate) type CreateFileWriter = fn(&Path) -> std::io::Result<Box<dyn Write>>;
+/// The writer for an individual output file. It must be [Send] so that a
+/// [crate::ShardedWriter] can be moved to another thread.
+pub(crate) type FileWriter = Box<dyn Write + Send>;
+
+/// Wraps the writer for each output file, eg, in a compressor.
+pub(crate) type CreateFileWriter =
+    Box<dyn FnMut(&Path, OpenMode, FileWriter) -> std::io::Result<FileWriter> + Send>;
+
+pub(crate) type OnFileCompletion<K> = Box<dyn FnMut(&FileCompleted<K>) + Send>;
+
+/// The size of an output file and, if checksums are enabled, the hash of its contents.
+#[derive(Default)]
+struct FileStats {
+    bytes: u64,
+    hasher: Option<Sha256>,
+}
+
+/// An output file that keeps its [FileStats] up to date with every byte written to it.
+///
+/// This sits directly on top of the [std::fs::File], beneath any compression added by
+/// [crate::ShardedWriter::on_create_file], so the stats describe the file as it is on disk. They
+/// are shared rather than owned because the writers layered on top may still write to the file
+/// as they're dropped, eg, to finish a compressed stream.
+struct TrackedFile {
+    file: std::fs::File,
+    stats: Arc<Mutex<FileStats>>,
+}
+
+impl Write for TrackedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.bytes += n as u64;
+        if let Some(hasher) = stats.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A completed output file, as listed in the manifest.
+pub(crate) struct CompletedFile {
+    pub key: String,
+    pub sequence: usize,
+    pub path: PathBuf,
+    pub rows: usize,
+    pub bytes: u64,
+    pub checksum: Option<String>,
+}
+
+impl CompletedFile {
+    /// The fields of this file's row in a manifest or checkpoint.
+    pub fn fields(&self) -> [String; 6] {
+        [
+            self.key.clone(),
+            self.sequence.to_string(),
+            self.path.to_string_lossy().into_owned(),
+            self.rows.to_string(),
+            self.bytes.to_string(),
+            self.checksum.clone().unwrap_or_default(),
+        ]
+    }
+
+    /// Parses the fields written by [CompletedFile::fields], starting at `start`.
+    pub fn from_fields(row: &StringRecord, start: usize) -> Result<Self, Error> {
+        Ok(Self {
+            key: checkpoint_field(row, start)?,
+            sequence: checkpoint_field(row, start + 1)?,
+            path: checkpoint_field::<String>(row, start + 2)?.into(),
+            rows: checkpoint_field(row, start + 3)?,
+            bytes: checkpoint_field(row, start + 4)?,
+            checksum: Some(checkpoint_field::<String>(row, start + 5)?).filter(|c| !c.is_empty()),
+        })
+    }
+}
+
+/// Settings and callbacks shared by every [Shard] of a [crate::ShardedWriter].
+///
+/// Shards don't hold their own copies of these; the writer lends its context to a shard
+/// whenever the shard needs to create, write or complete a file.
+pub(crate) struct ShardContext<K, FNameFile>
+where
+    FNameFile: ShardNaming<K>,
+{
+    /// How output files will be split up
+    pub splitting: FileSplitting,
+
+    /// The optional header row to be written to each sharded file.
+    pub header_record: Option<StringRecord>,
+
+    /// The CSV dialect (delimiter, quoting, terminator and so on) of every output file.
+    pub writer_builder: csv::WriterBuilder,
+
+    /// A function that wraps the buffered writer for each output file.
+    ///
+    /// By default, the buffered writer is used as it is, but if you want
+    /// to gzip output, for example, this function overrides that behavior.
+    pub create_file_writer: CreateFileWriter,
+
+    /// A function to be called when each sharded file is complete.
+    ///
+    /// A file is complete when the [crate::ShardedWriter] is finished or dropped or
+    /// when a new [ShardFile] is created for file splitting.
+    pub on_file_completion: Option<OnFileCompletion<K>>,
+
+    /// A function that defines how intermediate shard files are named.
+    ///
+    /// By default, files are named as `{shard}-{sequence}.{extension}`. For
+    /// example, "washington-7.csv" might be created when sharding on US
+    /// state names.
+    ///
+    /// You may override this with [`.with_output_shard_naming`]:
+    ///
+    /// ```ignore
+    /// let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
+    ///    .expect("Failed to create writer builder");
+    ///    .with_key_selector(|rec| rec.get(0).unwrap_or("unknown").to_owned());
+    ///    .with_output_shard_naming(|shard, seq| format!("{shard}-{seq}.csv"));
+    /// ```
+    pub create_output_filename: FNameFile,
+
+    /// The directory that output file names are relative to, if any.
+    pub output_directory: Option<PathBuf>,
+
+    /// Directories that are known to exist, so they needn't be created again for every file.
+    pub created_directories: HashSet<PathBuf>,
+
+    /// What to do when an output file already exists
+    pub existing_files: ExistingFilePolicy,
+
+    /// Every file completed so far, if a manifest is to be written
+    pub completed_files: Option<Vec<CompletedFile>>,
+
+    /// Whether to compute the SHA-256 checksum of every file
+    pub checksums: bool,
+
+    /// Whether files are written under a temporary name and renamed when complete
+    pub atomic_output: bool,
+
+    /// Whether two paths differing only in case are the same file
+    pub case_insensitive_paths: bool,
+
+    /// Every path produced so far, normalized for case if need be, and the key and sequence
+    /// number it was produced for.
+    pub produced_paths: HashMap<PathBuf, (String, usize)>,
+
+    /// The checkpoint file, once a checkpointed input is being processed. Each output file is
+    /// recorded in it as it's created, so that a resumed run knows which files are its own,
+    /// and, if there's to be a manifest, as it's completed.
+    pub checkpoint: Option<Writer<std::fs::File>>,
+
+    /// Files that an interrupted run created after its last checkpoint and that the resumed
+    /// run will write again, with the length each had before the interrupted run appended to
+    /// it, or `None` if the interrupted run created it.
+    pub resumable_files: HashMap<PathBuf, Option<u64>>,
+}
+
+impl<K, FNameFile> ShardContext<K, FNameFile>
+where
+    K: std::fmt::Display,
+    FNameFile: ShardNaming<K>,
+{
+    /// Builds the path of the `sequence`th file for `key`, creating its parent directory if
+    /// needed.
+    fn output_path(&mut self, key: &K, sequence: usize) -> Result<PathBuf, Error> {
+        let name = self.create_output_filename.file_name(key, sequence)?;
+        let path = match &self.output_directory {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !self.created_directories.contains(parent) {
+                std::fs::create_dir_all(parent)?;
+                self.created_directories.insert(parent.to_path_buf());
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// The form of `path` under which it's remembered, so that paths naming the same file match.
+    fn normalize_path(&self, path: &Path) -> PathBuf {
+        match self.case_insensitive_paths {
+            true => PathBuf::from(path.to_string_lossy().to_lowercase()),
+            false => path.to_path_buf(),
+        }
+    }
+
+    /// Whether a file created by this run already used `path`.
+    fn is_claimed(&self, path: &Path) -> bool {
+        self.produced_paths.contains_key(&self.normalize_path(path))
+    }
+
+    /// Records that `path` is used by the `sequence`th file for `key`, failing if another file
+    /// already used it.
+    fn claim_path(&mut self, path: &Path, key: &K, sequence: usize) -> Result<(), Error> {
+        let normalized = self.normalize_path(path);
+        if let Some((other_key, other_sequence)) = self.produced_paths.get(&normalized) {
+            return Err(Error::PathCollision {
+                path: path.to_path_buf(),
+                key: key.to_string(),
+                sequence,
+                other_key: other_key.clone(),
+                other_sequence: *other_sequence,
+            });
+        }
+        self.produced_paths
+            .insert(normalized, (key.to_string(), sequence));
+
+        Ok(())
+    }
+
+    /// Records in the checkpoint, if there is one, that the file at `path` is about to be
+    /// written by this run as the `sequence`th file for `key`, having had `original_len` bytes
+    /// if it's being appended to.
+    ///
+    /// The record is flushed straight away, since the file may be written before the next
+    /// checkpoint is.
+    fn record_created(
+        &mut self,
+        path: &Path,
+        original_len: Option<u64>,
+        key: &K,
+        sequence: usize,
+    ) -> Result<(), Error> {
+        let Some(checkpoint) = self.checkpoint.as_mut() else {
+            return Ok(());
+        };
+
+        checkpoint.write_record([
+            "created".to_owned(),
+            path.to_string_lossy().into_owned(),
+            original_len.map_or_else(String::new, |len| len.to_string()),
+            key.to_string(),
+            sequence.to_string(),
+        ])?;
+        checkpoint.flush()?;
+        Ok(())
+    }
+
+    /// Records that `path` was used by the `sequence`th file for `key` in a run being resumed.
+    pub fn restore_claim(&mut self, path: &Path, key: String, sequence: usize) {
+        let normalized = self.normalize_path(path);
+        self.produced_paths.insert(normalized, (key, sequence));
+    }
+}
 
 /// Represents an individual file written out.
 struct ShardFile {
+    /// The path the file will have once it's complete
     path: PathBuf,
-    key: String,
-    writer: Writer<Box<dyn Write>>,
+
+    /// The temporary path the file is written to until it's complete, when output is atomic
+    temp_path: Option<PathBuf>,
+
+    /// This file's sequence number within its shard
+    sequence: usize,
+
+    /// The open writer, or `None` if the file has been closed to free up its handle and
+    /// will be reopened for appending on the next write.
+    writer: Option<Writer<FileWriter>>,
     written: usize,
     splitting: FileSplitting,
+
+    /// The number of records written to the file, not counting the header
+    rows: usize,
+
+    /// The file's size and hash, as of the last time its writer was flushed
+    stats: Arc<Mutex<FileStats>>,
+
+    /// Where the first and last records written to the file were found in their input, if
+    /// they were read by a [csv::Reader]
+    first_record: Option<csv::Position>,
+    last_record: Option<csv::Position>,
 }
 
 impl ShardFile {
+    /// The path that records are currently being written to.
+    fn write_path(&self) -> &Path {
+        self.temp_path.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Opens the file at its write path with `mode` and layers a CSV writer on top of it, with
+    /// whatever `create_file_writer` adds in between.
+    fn open(
+        &mut self,
+        mode: OpenMode,
+        create_file_writer: &mut CreateFileWriter,
+        writer_builder: &csv::WriterBuilder,
+    ) -> std::io::Result<()> {
+        let path = self.write_path();
+        let file = match mode {
+            OpenMode::Create => std::fs::File::create(path)?,
+            OpenMode::Append => std::fs::OpenOptions::new().append(true).open(path)?,
+        };
+        let tracked = TrackedFile {
+            file,
+            stats: self.stats.clone(),
+        };
+
+        let inner = create_file_writer(path, mode, Box::new(BufWriter::new(tracked)))?;
+        self.writer = Some(writer_builder.from_writer(inner));
+        Ok(())
+    }
+
+    /// Locks the file's stats.
+    fn stats(&self) -> std::sync::MutexGuard<'_, FileStats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Flushes the writer, if it's open, all the way down to the file.
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes and closes the writer, if it's open.
+    fn close(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        if let Some(writer) = self.writer.take() {
+            // Dropping the writers layered on the file lets them finish writing, eg, a
+            // compressed stream's trailer.
+            drop(writer.into_inner().map_err(|e| e.into_error())?);
+        }
+
+        Ok(())
+    }
+
     /// Writes the `record` to this open file.
     ///
     /// This function bubbles up underdlying CSV writer errors on failure.
     /// On success, this returns true if and only if the file should be closed (we've met the conditions to split).
-    fn write_record(&mut self, record: &StringRecord) -> Result<bool, Error> {
-        self.writer.write_record(record)?;
+    fn write_record(
+        &mut self,
+        record: &StringRecord,
+        create_file_writer: &mut CreateFileWriter,
+        writer_builder: &csv::WriterBuilder,
+    ) -> Result<bool, Error> {
+        if self.writer.is_none() {
+            // The file was closed to stay under the open file limit; pick up where we left off.
+            // The header was already written when the file was created.
+            self.open(OpenMode::Append, create_file_writer, writer_builder)?;
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_record(record)?;
+        }
+        self.rows += 1;
+
+        if let Some(position) = record.position() {
+            self.first_record.get_or_insert_with(|| position.clone());
+            self.last_record = Some(position.clone());
+        }
 
         Ok(match self.splitting {
             FileSplitting::NoSplit => false,
@@ -40,126 +388,387 @@ impl ShardFile {
 }
 
 /// A logical sharded subset of the input data.
-pub(crate) struct Shard<FNameFile>
-where
-    FNameFile: Fn(&str, usize) -> String,
-{
+pub(crate) struct Shard<K> {
     /// The shard value
-    key: String,
+    key: K,
 
     /// The current, zero-based number identifying how many files have been
     /// written for this shard
     sequence: usize,
 
-    /// How output files will be split up
-    splitting: FileSplitting,
+    /// The number of files that have been started for this shard, which is less than
+    /// `sequence` if sequence numbers were skipped because their files already existed
+    files_written: usize,
 
     /// A reference to the [ShardFile], if one is open, for outputting rows.
     current_file: Option<ShardFile>,
 
-    /// The optional header row to be written to each sharded file.
-    header_record: Option<StringRecord>,
+    /// When this shard was last written to, used to find the least-recently-used shard when
+    /// the number of open files is capped.
+    last_used: u64,
 
-    /// A function that creates each output shard.
-    ///
-    /// By default, this will create a buffered text writer, but if you want
-    /// to gzip output, for example, this function overrides that behavior.
-    create_file_writer: CreateFileWriter,
+    /// Whether the last checkpoint has this shard as it is now, so it needn't be saved again.
+    saved: bool,
+}
 
-    /// A function to be called when each sharded file is complete.
-    ///
-    /// A file is complete when the Shard gets dropped, which is either when
-    /// the [ShardedWriter] is itself dropped or when a new [ShardFile] is
-    /// created for file splitting.
-    on_file_completion: Option<fn(&Path, &str)>,
+impl<K> Shard<K>
+where
+    K: std::fmt::Display,
+{
+    pub fn new(key: K) -> Self {
+        Self {
+            key,
+            sequence: 0,
+            files_written: 0,
+            current_file: None,
+            last_used: 0,
+            saved: false,
+        }
+    }
 
-    /// A function that defines how intermediate shard files are named.
+    /// Checks if this shard currently holds an open file handle.
+    pub fn is_open(&self) -> bool {
+        matches!(
+            &self.current_file,
+            Some(ShardFile {
+                writer: Some(_),
+                ..
+            })
+        )
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn last_used(&self) -> u64 {
+        self.last_used
+    }
+
+    pub fn set_last_used(&mut self, tick: u64) {
+        self.last_used = tick;
+    }
+
+    /// The number of files that have been started for this shard.
+    pub fn files_written(&self) -> usize {
+        self.files_written
+    }
+
+    /// Flushes and closes the underlying file handle without completing the file.
     ///
-    /// By default, files are named as `{shard}-{sequence}.{extension}`. For
-    /// example, "washington-7.csv" might be created when sharding on US
-    /// state names.
+    /// The next record written to this shard will reopen the same file in append mode, so the
+    /// sequence number and split counters carry on as if the file had never been closed.
+    pub fn close_handle(&mut self) -> Result<(), crate::Error> {
+        if let Some(file) = self.current_file.as_mut() {
+            file.close()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the current file, if any, and writes this shard's state to a checkpoint as a
+    /// single row: the key, sequence number, number of files written, and, if a file is open,
+    /// its path, temporary path, length in bytes, split counter, rows written, and first and
+    /// last input record positions. Nothing is written if the shard hasn't been written to since
+    /// it was last saved.
+    pub fn save<W: Write>(&mut self, checkpoint: &mut Writer<W>) -> Result<(), Error> {
+        if self.saved {
+            return Ok(());
+        }
+
+        let mut row = vec![
+            "shard".to_owned(),
+            self.key.to_string(),
+            self.sequence.to_string(),
+            self.files_written.to_string(),
+        ];
+
+        if let Some(file) = self.current_file.as_mut() {
+            file.flush()?;
+
+            let len = file.stats().bytes;
+            let temp_path = file.temp_path.as_deref().unwrap_or(Path::new(""));
+            row.push(file.path.to_string_lossy().into_owned());
+            row.push(temp_path.to_string_lossy().into_owned());
+            row.push(len.to_string());
+            row.push(file.written.to_string());
+            row.push(file.rows.to_string());
+            row.push(position_field(file.first_record.as_ref()));
+            row.push(position_field(file.last_record.as_ref()));
+        }
+
+        checkpoint.write_record(&row)?;
+        self.saved = true;
+        Ok(())
+    }
+
+    /// Recreates the shard for `key` from a row written by [Shard::save], truncating its open
+    /// file, if any, to the length it had when the checkpoint was written.
     ///
-    /// You may override this with [`.with_output_shard_naming`]:
-    /// 
-    /// ```
-    /// let mut shard_writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)
-    ///    .expect("Failed to create writer builder");
-    ///    .with_key_selector(|rec| rec.get(0).unwrap_or("unknown").to_owned());
-    ///    .with_output_shard_naming(|shard, seq| format!("{shard}-{seq}.csv"));
-    /// ```
-    create_output_filename: Rc<FNameFile>,
-}
+    /// The file is left closed, so the next record written reopens it for appending.
+    pub fn restore<FNameFile>(
+        key: K,
+        row: &StringRecord,
+        ctx: &mut ShardContext<K, FNameFile>,
+    ) -> Result<Self, Error>
+    where
+        FNameFile: ShardNaming<K>,
+    {
+        let mut shard = Self::new(key);
+        shard.saved = true;
+        shard.sequence = checkpoint_field(row, 2)?;
+        shard.files_written = checkpoint_field(row, 3)?;
 
-impl<FNameFile> Shard<FNameFile>
-where
-    FNameFile: Fn(&str, usize) -> String,
-{
-    fn path(&self) -> std::path::PathBuf {
-        (self.create_output_filename)(&self.key, self.sequence).into()
+        if row.len() > 4 {
+            let path = PathBuf::from(checkpoint_field::<String>(row, 4)?);
+            let temp_path = Some(PathBuf::from(checkpoint_field::<String>(row, 5)?))
+                .filter(|p| !p.as_os_str().is_empty());
+            let len: u64 = checkpoint_field(row, 6)?;
+
+            if let Some(temp_path) = &temp_path {
+                if !temp_path.exists() && path.exists() {
+                    // The file was split and completed after the checkpoint, so it's about to be
+                    // written again. Leave the completed file in place until it's replaced.
+                    std::fs::copy(&path, temp_path)?;
+                }
+            }
+
+            let file = ShardFile {
+                path,
+                temp_path,
+                sequence: shard.sequence - 1,
+                writer: None,
+                written: checkpoint_field(row, 7)?,
+                splitting: ctx.splitting,
+                rows: checkpoint_field(row, 8)?,
+                stats: Arc::default(),
+                first_record: parse_position(row, 9)?,
+                last_record: parse_position(row, 10)?,
+            };
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(file.write_path())?
+                .set_len(len)?;
+            *file.stats() = FileStats {
+                bytes: len,
+                hasher: ctx
+                    .checksums
+                    .then(|| hash_file(file.write_path()))
+                    .transpose()?,
+            };
+
+            shard.current_file = Some(file);
+        }
+
+        Ok(shard)
     }
 
-    pub fn new(
-        splitting: FileSplitting,
-        key: String,
+    pub fn write_record<FNameFile>(
+        &mut self,
+        record: &StringRecord,
+        ctx: &mut ShardContext<K, FNameFile>,
+    ) -> Result<(), crate::Error>
+    where
+        FNameFile: ShardNaming<K>,
+    {
+        self.saved = false;
+        if self.current_file.is_none() {
+            // Start a new file
+            let shard_file = self.create_file(ctx)?;
+            self.current_file = Some(shard_file);
+        }
 
-        header_record: Option<StringRecord>,
-        create_file_writer: CreateFileWriter,
-        create_output_filename: Rc<FNameFile>,
-        on_file_completion: Option<fn(&Path, &str)>,
-    ) -> Self {
-        Self {
-            splitting,
-            current_file: None,
-            header_record,
-            on_file_completion,
-            key,
-            sequence: 0,
-            create_output_filename,
-            create_file_writer,
-        }
-    }
-
-    pub fn write_record(&mut self, record: &StringRecord) -> Result<(), crate::Error> {
-        match self.current_file.as_mut() {
-            Some(sf) => {
-                // File is already in-progress
-                if sf.write_record(record)? {
-                    // And we should wrap this one up.
-                    if let Some(s) = self.current_file.take() {
-                        if let Some(callback) = &self.on_file_completion {
-                            let ShardFile {
-                                path, key, writer, ..
-                            } = s;
-                            drop(writer);
-                            callback(&path, &key);
-                        }
-                    }
+        if let Some(sf) = self.current_file.as_mut() {
+            if sf.write_record(record, &mut ctx.create_file_writer, &ctx.writer_builder)? {
+                // We've met the conditions to split, so wrap this one up.
+                self.complete_file(ctx, CompletionReason::Split)
+                    .map_err(|e| crate::Error::Close(vec![e]))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the next file in this shard's sequence and writes the header to it.
+    fn create_file<FNameFile>(
+        &mut self,
+        ctx: &mut ShardContext<K, FNameFile>,
+    ) -> Result<ShardFile, crate::Error>
+    where
+        FNameFile: ShardNaming<K>,
+    {
+        let mut path = ctx.output_path(&self.key, self.sequence)?;
+        if ctx.existing_files == ExistingFilePolicy::NextSequence {
+            // Paths this run has used are skipped too, even if, say, the file is still being
+            // written under a temporary name or the filesystem is case-sensitive after all.
+            while (path.exists() && !ctx.resumable_files.contains_key(&path))
+                || ctx.is_claimed(&path)
+            {
+                self.sequence += 1;
+                let next = ctx.output_path(&self.key, self.sequence)?;
+                if next == path {
+                    // The name doesn't depend on the sequence number, so no free one will be found.
+                    return Err(Error::FileExists(path));
                 }
+                path = next;
+            }
+        }
+
+        ctx.claim_path(&path, &self.key, self.sequence)?;
+
+        // A file the interrupted run being resumed wrote is treated as it was before that run.
+        let resumed = ctx.resumable_files.remove(&path);
+        let existing_len = match resumed {
+            Some(original_len) => original_len,
+            None => match std::fs::metadata(&path) {
+                Ok(metadata) => Some(metadata.len()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let mode = match (ctx.existing_files, existing_len) {
+            (ExistingFilePolicy::Error, Some(_)) => return Err(Error::FileExists(path)),
+            (ExistingFilePolicy::Append, Some(_)) => OpenMode::Append,
+            _ => OpenMode::Create,
+        };
+        let original_len = existing_len.filter(|_| mode == OpenMode::Append);
+        ctx.record_created(&path, original_len, &self.key, self.sequence)?;
+
+        let temp_path = ctx.atomic_output.then(|| temp_path(&path));
+        if let (Some(temp_path), OpenMode::Append) = (&temp_path, mode) {
+            // Append to a copy so the original is untouched until the copy replaces it.
+            std::fs::copy(&path, temp_path)?;
+        }
+
+        let write_path = temp_path.as_deref().unwrap_or(&path);
+        if let (Some(_), Some(len), OpenMode::Append) = (resumed, existing_len, mode) {
+            // Drop whatever the interrupted run appended.
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(write_path)?
+                .set_len(len)?;
+        }
+        // The size and checksum cover the whole file, including what was already there.
+        let stats = match mode {
+            OpenMode::Create => FileStats {
+                bytes: 0,
+                hasher: ctx.checksums.then(Sha256::new),
+            },
+            OpenMode::Append => FileStats {
+                bytes: std::fs::metadata(write_path)?.len(),
+                hasher: ctx.checksums.then(|| hash_file(write_path)).transpose()?,
+            },
+        };
+
+        let sequence = self.sequence;
+        let mut file = ShardFile {
+            path,
+            temp_path,
+            sequence,
+            writer: None,
+            written: 0,
+            splitting: ctx.splitting,
+            rows: 0,
+            stats: Arc::new(Mutex::new(stats)),
+            first_record: None,
+            last_record: None,
+        };
+        file.open(mode, &mut ctx.create_file_writer, &ctx.writer_builder)?;
+
+        if let (Some(h), Some(writer)) = (&ctx.header_record, file.writer.as_mut()) {
+            if mode == OpenMode::Create || existing_len == Some(0) {
+                writer.write_record(h)?;
             }
-            None => {
-                // Start a new file
-                let writer = (self.create_file_writer)(&self.path())?;
-                let mut writer = Writer::from_writer(writer);
+        }
 
-                if let Some(h) = &self.header_record {
-                    writer.write_record(h)?;
+        self.sequence += 1;
+        self.files_written += 1;
+
+        Ok(file)
+    }
+
+    /// Flushes and closes the current file, if any, renames it to its final path if it was
+    /// written under a temporary one, then notifies the completion callback.
+    ///
+    /// The callback is not called if the file couldn't be flushed or renamed.
+    pub fn complete_file<FNameFile>(
+        &mut self,
+        ctx: &mut ShardContext<K, FNameFile>,
+        reason: CompletionReason,
+    ) -> Result<(), crate::FileError>
+    where
+        FNameFile: ShardNaming<K>,
+    {
+        if let Some(mut file) = self.current_file.take() {
+            // Closing the writer also closes the file handle.
+            if let Err(error) = file.close() {
+                let key = self.key.to_string();
+                return Err(crate::FileError {
+                    path: file.path,
+                    key,
+                    error,
+                });
+            }
+
+            if let Some(temp_path) = &file.temp_path {
+                if let Err(error) = std::fs::rename(temp_path, &file.path) {
+                    let key = self.key.to_string();
+                    return Err(crate::FileError {
+                        path: file.path,
+                        key,
+                        error,
+                    });
                 }
+            }
 
-                let mut shard_file = ShardFile {
-                    path: self.path(),
-                    key: self.key.to_owned(),
-                    writer,
-                    written: 0,
-                    splitting: self.splitting,
-                };
+            let (bytes, hasher) = {
+                let mut stats = file.stats();
+                (stats.bytes, stats.hasher.take())
+            };
+            let checksum = hasher.map(|h| format!("{:x}", h.finalize()));
 
-                self.sequence += 1;
+            if let Some(completed_files) = ctx.completed_files.as_mut() {
+                let completed = CompletedFile {
+                    key: self.key.to_string(),
+                    sequence: file.sequence,
+                    path: file.path.clone(),
+                    rows: file.rows,
+                    bytes,
+                    checksum: checksum.clone(),
+                };
 
-                // This seems an unnecessary step -- but if we only want to write one row or very few bytes to
-                // a stream, we'll preserve this check.
-                if !shard_file.write_record(record)? {
-                    self.current_file = Some(shard_file);
+                // Recorded for the manifest of a resumed run; it's flushed with the next
+                // checkpoint, which is the first that can include the file.
+                if let Some(checkpoint) = ctx.checkpoint.as_mut() {
+                    let mut row = vec!["completed".to_owned()];
+                    row.extend(completed.fields());
+                    if let Err(e) = checkpoint.write_record(&row) {
+                        let key = self.key.to_string();
+                        let error = std::io::Error::other(e);
+                        return Err(crate::FileError {
+                            path: file.path,
+                            key,
+                            error,
+                        });
+                    }
                 }
+
+                completed_files.push(completed);
+            }
+
+            // *Then* call back to the client because now the file is definitely dropped.
+            if let Some(callback) = ctx.on_file_completion.as_mut() {
+                callback(&FileCompleted {
+                    path: &file.path,
+                    key: &self.key,
+                    sequence: file.sequence,
+                    rows: file.rows,
+                    bytes,
+                    checksum: checksum.as_deref(),
+                    reason,
+                    first_record: file.first_record.as_ref(),
+                    last_record: file.last_record.as_ref(),
+                });
             }
         }
 
@@ -167,22 +776,58 @@ where
     }
 }
 
-impl<FNameFile> Drop for Shard<FNameFile>
-where
-    FNameFile: Fn(&str, usize) -> String,
-{
-    fn drop(&mut self) {
-        if let Some(ShardFile {
-            path, key, writer, ..
-        }) = self.current_file.take()
-        {
-            if let Some(callback) = &self.on_file_completion {
-                // Explicitly drop the writer so the file gets flushed and the handle closed.
-                drop(writer);
-
-                // *Then* call back to the client because now the file is definitely dropped.
-                callback(&path, &key);
-            }
-        }
+/// The temporary path that the file at `path` is written to when output is atomic: a hidden
+/// file in the same directory, so it can be renamed into place without copying.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".inprogress");
+    path.with_file_name(name)
+}
+
+/// Parses the field at `index` of a checkpoint row.
+pub(crate) fn checkpoint_field<T: std::str::FromStr>(
+    row: &StringRecord,
+    index: usize,
+) -> Result<T, Error> {
+    row.get(index)
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| Error::Checkpoint(format!("bad field {index} in row {row:?}")))
+}
+
+/// Hashes the contents of the file at `path`.
+fn hash_file(path: &Path) -> std::io::Result<Sha256> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher)
+}
+
+/// Formats an input position for a checkpoint as `byte:line:record`, or empty if there is none.
+fn position_field(position: Option<&csv::Position>) -> String {
+    position.map_or_else(String::new, |p| {
+        format!("{}:{}:{}", p.byte(), p.line(), p.record())
+    })
+}
+
+/// Parses an input position written by [position_field] from the field at `index`.
+fn parse_position(row: &StringRecord, index: usize) -> Result<Option<csv::Position>, Error> {
+    let field: String = checkpoint_field(row, index)?;
+    if field.is_empty() {
+        return Ok(None);
     }
+
+    let parts = field
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()
+        .filter(|parts| parts.len() == 3)
+        .ok_or_else(|| Error::Checkpoint(format!("bad position '{field}'")))?;
+
+    let mut position = csv::Position::new();
+    position
+        .set_byte(parts[0])
+        .set_line(parts[1])
+        .set_record(parts[2]);
+    Ok(Some(position))
 }
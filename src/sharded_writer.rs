@@ -1,12 +1,47 @@
-use crate::{shard, Error, FileSplitting};
+use crate::{
+    input::{self, InputRow, RawInput},
+    key::{ColumnKey, ColumnsKey, KeyError, KeySelector, MultiKey, OptionalKey, TryKey},
+    naming::{KeySanitizer, Sanitized, ShardNaming},
+    shard, CompletionReason, Error, ExistingFilePolicy, FileCompleted, FileError, FileSplitting,
+    MalformedRowPolicy, OpenMode, ProcessSummary, RunSummary,
+};
 use csv::StringRecord;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    io::{BufWriter, Write},
-    path::Path,
-    rc::Rc,
+    borrow::Borrow,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic, mpsc},
 };
 
+/// How many records a worker in [ShardedWriter::process_files_parallel] parses before handing
+/// them off to be written.
+const PARALLEL_BATCH_SIZE: usize = 1024;
+
+/// Where and how often a [ShardedWriter] saves its progress through an input file.
+struct Checkpointing {
+    /// The checkpoint file
+    path: PathBuf,
+
+    /// How many input rows are processed between checkpoints
+    every: usize,
+
+    /// The input file being processed, if it's one that can be resumed
+    input: Option<String>,
+
+    /// How many input rows have been processed since the last checkpoint
+    since_last: usize,
+
+    /// Input files that the run being resumed finished, each of which is skipped once
+    done: Vec<String>,
+
+    /// The input file that the run being resumed was part way through, and where to resume it
+    resume_at: Option<(String, csv::Position)>,
+}
+
 pub struct ShardedWriterBuilder {
     header: Option<StringRecord>,
 }
@@ -43,18 +78,135 @@ impl ShardedWriterBuilder {
         Ok(Self { header })
     }
 
+    /// Finds the zero-based index of the column called `name` in the header.
+    ///
+    /// This fails with [`Error::MissingColumn`] if there is no such column or no header at all.
+    pub fn column_index(&self, name: &str) -> Result<usize, Error> {
+        self.header
+            .as_ref()
+            .and_then(|h| h.iter().position(|column| column == name))
+            .ok_or_else(|| Error::MissingColumn(name.to_owned()))
+    }
+
+    /// Specifies how the input will be sharded using any [KeySelector], such as one of the
+    /// built-in selectors or your own implementation.
+    pub fn with_selector<S>(self, key_selector: S) -> ShardedWriterWithKey<S>
+    where
+        S: KeySelector,
+    {
+        ShardedWriterWithKey {
+            header: self.header,
+            key_selector,
+        }
+    }
+
+    /// Shards the input on the value of the column called `name`.
+    ///
+    /// The column is looked up in the header now rather than for every row, so this fails with
+    /// [`Error::MissingColumn`] if the header doesn't have it. Unlike a hard-coded index, this
+    /// keeps working if upstream data reorders its columns.
+    ///
+    /// ```
+    /// # use shard_csv::*;
+    /// # let mut csv_reader = csv::Reader::from_reader("id,language\n".as_bytes());
+    /// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+    ///     .key_by_column("language")?
+    ///     .with_output_shard_naming(|lang, seq| format!("{lang}-{seq}.csv"));
+    /// # Ok::<(), shard_csv::Error>(())
+    /// ```
+    pub fn key_by_column(self, name: &str) -> Result<ShardedWriterWithKey<ColumnKey>, Error> {
+        let index = self.column_index(name)?;
+        Ok(self.with_selector(ColumnKey::new(index)))
+    }
+
+    /// Shards the input on the values of the columns called `names`, joined with `separator`.
+    ///
+    /// As with [`ShardedWriterBuilder::key_by_column`], the columns are looked up in the header
+    /// now, failing with [`Error::MissingColumn`] for the first that's missing.
+    ///
+    /// ```
+    /// # use shard_csv::*;
+    /// # let mut csv_reader = csv::Reader::from_reader("id,country,state\n".as_bytes());
+    /// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+    ///     .key_by_columns(&["country", "state"], "/")?
+    ///     .with_output_shard_naming(|key, seq| format!("{key}/part-{seq}.csv"));
+    /// # Ok::<(), shard_csv::Error>(())
+    /// ```
+    pub fn key_by_columns(
+        self,
+        names: &[&str],
+        separator: &str,
+    ) -> Result<ShardedWriterWithKey<ColumnsKey>, Error> {
+        let indices = names
+            .iter()
+            .map(|name| self.column_index(name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.with_selector(ColumnsKey::new(indices, separator)))
+    }
+
     /// Specifies how the input will be sharded.
     ///
     /// Given a row of input, the key selector determines which shard the record belongs in.
-    pub fn with_key_selector<FKey>(self, key_selector: FKey) -> ShardedWriterWithKey<FKey>
+    /// Keys may be of any type that is `Hash + Eq + Clone + Display`; see [KeySelector].
+    pub fn with_key_selector<FKey, K>(self, key_selector: FKey) -> ShardedWriterWithKey<FKey>
     where
-        FKey: Fn(&StringRecord) -> String,
+        FKey: Fn(&StringRecord) -> K,
     {
         ShardedWriterWithKey {
             header: self.header,
             key_selector,
         }
     }
+
+    /// Specifies how the input will be sharded when some records should be dropped.
+    ///
+    /// Given a row of input, the key selector returns the shard the record belongs in, or `None`
+    /// to skip the record. See [OptionalKey] for details.
+    pub fn with_optional_key_selector<F, K>(
+        self,
+        key_selector: F,
+    ) -> ShardedWriterWithKey<OptionalKey<F>>
+    where
+        F: Fn(&StringRecord) -> Option<K>,
+    {
+        ShardedWriterWithKey {
+            header: self.header,
+            key_selector: OptionalKey(key_selector),
+        }
+    }
+
+    /// Specifies how the input will be sharded when selecting a key can fail.
+    ///
+    /// Given a row of input, the key selector returns the shard the record belongs in or an
+    /// error, eg, because the column being sharded on doesn't parse. Errors are handled like
+    /// malformed rows: by default processing stops with [`Error::KeySelector`], but see
+    /// [`ShardedWriter::with_malformed_rows`] to skip or quarantine such rows instead.
+    pub fn with_try_key_selector<F, K, E>(self, key_selector: F) -> ShardedWriterWithKey<TryKey<F>>
+    where
+        F: Fn(&StringRecord) -> Result<K, E>,
+        E: Into<KeyError>,
+    {
+        ShardedWriterWithKey {
+            header: self.header,
+            key_selector: TryKey(key_selector),
+        }
+    }
+
+    /// Specifies how the input will be sharded when records may belong to several shards.
+    ///
+    /// Given a row of input, the key selector returns the keys of every shard the record belongs
+    /// in, and the record is written to each of them. See [MultiKey] for details.
+    pub fn with_multi_key_selector<F, I>(self, key_selector: F) -> ShardedWriterWithKey<MultiKey<F>>
+    where
+        F: Fn(&StringRecord) -> I,
+        I: IntoIterator,
+    {
+        ShardedWriterWithKey {
+            header: self.header,
+            key_selector: MultiKey(key_selector),
+        }
+    }
 }
 
 pub struct ShardedWriterWithKey<FKey> {
@@ -64,19 +216,57 @@ pub struct ShardedWriterWithKey<FKey> {
 
 impl<FKey> ShardedWriterWithKey<FKey>
 where
-    FKey: Fn(&StringRecord) -> String,
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display,
 {
     /// Specifies how output shard files will be named.
     ///
-    /// The specified function will be called with the key value (derived from the `key_selector`
-    /// passed to [`ShardedWriterBuilder::with_key_selector`]) and the current sequence number,
-    /// which is a zero-based number identifying how many files have been written for this shard.
+    /// The specified function will be called with the typed key value (derived from the
+    /// `key_selector` passed to [`ShardedWriterBuilder::with_key_selector`]) and the current
+    /// sequence number, which is a zero-based number identifying how many files have been written
+    /// for this shard.
     pub fn with_output_shard_naming<FNameFile>(
         self,
         create_output_filename: FNameFile,
     ) -> ShardedWriter<FKey, FNameFile>
     where
-        FNameFile: Fn(&str, usize) -> String,
+        FNameFile: Fn(&FKey::Key, usize) -> String,
+    {
+        self.with_naming(create_output_filename)
+    }
+
+    /// Specifies how output shard files will be named, from keys made safe for file names by
+    /// `sanitizer`.
+    ///
+    /// The specified function is called with the sanitized [Display] form of each key and the
+    /// current sequence number. Keys the sanitizer rejects, such as `..`, fail with
+    /// [`Error::UnsafeKey`]. Completion callbacks still receive the original, typed key.
+    ///
+    /// ```
+    /// # use shard_csv::*;
+    /// # let mut csv_reader = csv::Reader::from_reader("id,customer\n".as_bytes());
+    /// let writer = ShardedWriterBuilder::new_from_csv_reader(&mut csv_reader)?
+    ///     .key_by_column("customer")?
+    ///     .with_sanitized_shard_naming(KeySanitizer::new(), |key, seq| format!("{key}-{seq}.csv"));
+    /// # Ok::<(), shard_csv::Error>(())
+    /// ```
+    pub fn with_sanitized_shard_naming<F>(
+        self,
+        sanitizer: KeySanitizer,
+        naming: F,
+    ) -> ShardedWriter<FKey, Sanitized<F>>
+    where
+        F: Fn(&str, usize) -> String,
+    {
+        self.with_naming(Sanitized { sanitizer, naming })
+    }
+
+    fn with_naming<FNameFile>(
+        self,
+        create_output_filename: FNameFile,
+    ) -> ShardedWriter<FKey, FNameFile>
+    where
+        FNameFile: ShardNaming<FKey::Key>,
     {
         let ShardedWriterWithKey {
             header,
@@ -84,114 +274,375 @@ where
         } = self;
 
         ShardedWriter {
-            header_record: header,
+            reader_builder: input_builder(header.is_some()),
             key_selector,
-            output_splitting: FileSplitting::NoSplit,
-            output_delimiter: b',',
-            on_file_completion: None,
-            create_file_writer: default_create_file_writer,
-            create_output_filename: Rc::new(create_output_filename),
+            keys: Vec::new(),
+            max_open_files: None,
+            malformed_rows: MalformedRowPolicy::FailFast,
+            dead_letter: None,
+            checkpoint: None,
+            manifest: None,
+            context: shard::ShardContext {
+                splitting: FileSplitting::NoSplit,
+                header_record: header,
+                writer_builder: csv::WriterBuilder::new(),
+                create_file_writer: Box::new(default_create_file_writer),
+                on_file_completion: None,
+                create_output_filename,
+                output_directory: None,
+                created_directories: HashSet::new(),
+                existing_files: ExistingFilePolicy::Overwrite,
+                completed_files: None,
+                checksums: false,
+                atomic_output: false,
+                case_insensitive_paths: cfg!(any(windows, target_os = "macos")),
+                produced_paths: HashMap::new(),
+                checkpoint: None,
+                resumable_files: HashMap::new(),
+            },
             handles: HashMap::new(),
+            totals: ProcessSummary::default(),
+            clock: 0,
+            open_shards: BTreeMap::new(),
         }
     }
 }
 
 pub struct ShardedWriter<FKey, FNameFile>
 where
-    FNameFile: Fn(&str, usize) -> String,
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display,
+    FNameFile: ShardNaming<FKey::Key>,
 {
-    /// How the input file should be split
-    output_splitting: FileSplitting,
+    /// The CSV dialect of input parsed by the writer, eg, by [`ShardedWriter::process_file`]
+    reader_builder: csv::ReaderBuilder,
 
-    /// The field delimiter; default is ','
-    output_delimiter: u8,
+    /// The maximum number of output files that may be open at once, if any
+    max_open_files: Option<usize>,
 
-    /// A closure that accepts a CSV row and returns a String identifying which shard it belongs to.
-    key_selector: FKey,
+    /// What to do with input rows that can't be parsed
+    malformed_rows: MalformedRowPolicy,
 
-    /// An optional header record that will be written to every output file.
-    header_record: Option<StringRecord>,
+    /// The dead-letter file for [`MalformedRowPolicy::Quarantine`], created on first use
+    dead_letter: Option<csv::Writer<std::fs::File>>,
 
-    /// A function that will be called when an intermediate file is completed
-    on_file_completion: Option<fn(&Path, &str)>,
+    /// Periodic checkpointing of progress through input files, if enabled
+    checkpoint: Option<Checkpointing>,
 
-    create_output_filename: Rc<FNameFile>,
+    /// Where to write the manifest of completed files, if anywhere
+    manifest: Option<PathBuf>,
 
-    /// A function that creates a writer for a requested output file path
-    create_file_writer: crate::shard::CreateFileWriter,
+    /// Accepts a CSV row and identifies which shard or shards it belongs to.
+    key_selector: FKey,
+
+    /// Scratch space for the keys selected for each record
+    keys: Vec<FKey::Key>,
+
+    /// The splitting, header, file naming and callbacks shared by every shard
+    context: shard::ShardContext<FKey::Key, FNameFile>,
 
     /// A mapping of shard keys to the shards that output to files
-    handles: HashMap<String, shard::Shard<FNameFile>>,
+    handles: HashMap<FKey::Key, shard::Shard<FKey::Key>>,
+
+    /// The total number of records written and skipped across all calls to process data
+    totals: ProcessSummary,
+
+    /// A counter incremented for every record written, used to order shards by recency
+    clock: u64,
+
+    /// The keys of shards with open files, ordered from least- to most-recently used. This
+    /// is only maintained when `max_open_files` is set.
+    open_shards: BTreeMap<u64, FKey::Key>,
 }
 
 impl<FKey, FNameFile> std::fmt::Debug for ShardedWriter<FKey, FNameFile>
 where
-    FNameFile: Fn(&str, usize) -> String,
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display,
+    FNameFile: ShardNaming<FKey::Key>,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ShardedWriter")
-            .field("output_splitting", &self.output_splitting)
-            .field("delimiter", &self.output_delimiter)
+            .field("output_splitting", &self.context.splitting)
+            .field("input_dialect", &self.reader_builder)
+            .field("output_dialect", &self.context.writer_builder)
+            .field("output_directory", &self.context.output_directory)
+            .field("max_open_files", &self.max_open_files)
+            .field("malformed_rows", &self.malformed_rows)
             .finish()
     }
 }
 
 impl<FKey, FNameFile> ShardedWriter<FKey, FNameFile>
 where
-    FKey: Fn(&StringRecord) -> String,
-    FNameFile: Fn(&str, usize) -> String,
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display,
+    FNameFile: ShardNaming<FKey::Key>,
 {
-    /// Creates a new writer.
+    /// Specifies when sharded output files should be split.
+    pub fn with_output_splitting(mut self, output_splitting: FileSplitting) -> Self {
+        self.context.splitting = output_splitting;
+        self
+    }
+
+    /// Sets the field delimiter to be used for both input and output files. Default is ','.
     ///
-    /// You must specify the directory into which the output will be written, a function
-    /// that extracts the shard key from a csv [StringRecord], and how output files will
-    /// be named. The file naming function accepts the shard key and a zero-based number
-    /// indicating how many files have been created for this shard.
+    /// To convert between formats while sharding, set the delimiters separately with
+    /// [`ShardedWriter::with_input_delimiter`] and [`ShardedWriter::with_output_delimiter`].
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.reader_builder.delimiter(delimiter);
+        self.context.writer_builder.delimiter(delimiter);
+        self
+    }
+
+    /// Sets the field delimiter used to parse input read by [`ShardedWriter::process_file`],
+    /// [`ShardedWriter::process_reader`] and [`ShardedWriter::process_files_parallel`].
+    /// Default is ','.
+    pub fn with_input_delimiter(mut self, delimiter: u8) -> Self {
+        self.reader_builder.delimiter(delimiter);
+        self
+    }
+
+    /// Sets the field delimiter to be used for output files. Default is ','.
+    pub fn with_output_delimiter(mut self, delimiter: u8) -> Self {
+        self.context.writer_builder.delimiter(delimiter);
+        self
+    }
+
+    /// Configures the CSV dialect used to parse input, such as quoting, comments and
+    /// terminators.
     ///
-    /// This function can return an error if the output directory can't be created.
+    /// The closure is given the [csv::ReaderBuilder] that input files are read with:
     ///
+    /// ```ignore
+    /// shard_writer = shard_writer.with_input_dialect(|b| b.delimiter(b'|').comment(Some(b'#')));
     /// ```
-    /// let writer = ShardedWriter::new(
-    ///     "./foo-sharded/",
-    ///     |record| record.get(7).unwrap_or("_unknown").to_string(),
-    ///     |shard, seq| format!("{shard}-file{seq}.csv")
-    /// )?;
+    ///
+    /// Whether the input has a header is always taken from the [ShardedWriterBuilder], so any
+    /// `has_headers` setting made here is overridden. Readers passed to
+    /// [`ShardedWriter::process_csv`] are already configured and aren't affected.
+    pub fn with_input_dialect<F>(mut self, configure: F) -> Self
+    where
+        F: FnOnce(&mut csv::ReaderBuilder) -> &mut csv::ReaderBuilder,
+    {
+        configure(&mut self.reader_builder);
+        self.reader_builder
+            .has_headers(self.context.header_record.is_some());
+        self
+    }
+
+    /// Configures the CSV dialect of output files, such as quoting and line terminators.
+    ///
+    /// The closure is given the [csv::WriterBuilder] that every output file is created with:
+    ///
+    /// ```ignore
+    /// shard_writer = shard_writer.with_output_dialect(|b| {
+    ///     b.quote_style(csv::QuoteStyle::Always)
+    ///         .terminator(csv::Terminator::CRLF)
+    /// });
     /// ```
+    ///
+    /// This only affects output, so input and output formats can differ; for example, sharding
+    /// pipe-delimited input into TSV files.
+    pub fn with_output_dialect<F>(mut self, configure: F) -> Self
+    where
+        F: FnOnce(&mut csv::WriterBuilder) -> &mut csv::WriterBuilder,
+    {
+        configure(&mut self.context.writer_builder);
+        self
+    }
 
-    /// Specifies when sharded output files should be split.
-    pub fn with_output_splitting(mut self, output_splitting: FileSplitting) -> Self {
-        self.output_splitting = output_splitting;
+    /// Writes output files under `directory`.
+    ///
+    /// Names returned by the closure given to [`ShardedWriterWithKey::with_output_shard_naming`]
+    /// are joined onto this directory, so they may be relative; an absolute name is used as-is.
+    /// Whether or not this is set, any missing parent directories of an output file are created
+    /// before the file is, so names like `dt=2026-10-16/part-0.csv` work without creating the
+    /// directory for each key up front.
+    pub fn with_output_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.context.output_directory = Some(directory.into());
         self
     }
 
-    /// Sets the field delimiter to be used for output files. Default is ','.
-    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
-        self.output_delimiter = delimiter;
+    /// Specifies what happens when an output file is about to be created where a file already
+    /// exists. The default is [`ExistingFilePolicy::Overwrite`].
+    ///
+    /// With [`ExistingFilePolicy::Append`], the file is opened with [`OpenMode::Append`], and
+    /// splitting counts only the rows or bytes written by this run.
+    pub fn with_existing_files(mut self, policy: ExistingFilePolicy) -> Self {
+        self.context.existing_files = policy;
+        self
+    }
+
+    /// Writes each output file under a temporary name and renames it into place once it's
+    /// complete, so anything watching for new files never sees one half-written.
+    ///
+    /// A file named `part-0.csv` is written as `.part-0.csv.inprogress` in the same directory,
+    /// then flushed, closed and renamed to `part-0.csv` just before
+    /// [`ShardedWriter::on_file_completion`] is called with the final path. Files are only
+    /// renamed when they're split or by [`ShardedWriter::finish`]; if the writer is dropped
+    /// without being finished, eg, because processing failed, its open files are closed but left
+    /// under their temporary names and no completion callback is called. The function passed
+    /// to [`ShardedWriter::on_create_file`] is given the temporary path. When appending to an
+    /// existing file with [`ExistingFilePolicy::Append`], the file is copied to the temporary
+    /// path and the copy is appended to, replacing the original only once it's complete. If the
+    /// run fails or is interrupted, the original is left as it was and the partly appended copy
+    /// stays behind under its temporary name.
+    pub fn with_atomic_output(mut self, atomic: bool) -> Self {
+        self.context.atomic_output = atomic;
+        self
+    }
+
+    /// Computes the SHA-256 checksum of every output file as it's written, so files needn't be
+    /// read back to verify them.
+    ///
+    /// The checksum is of the file's contents as stored, after any compression added by
+    /// [`ShardedWriter::on_create_file`], so it can be compared with one computed from the file
+    /// later. It covers the whole file, including anything already in it when appending with
+    /// [`ExistingFilePolicy::Append`], and is given to the completion callback and listed in
+    /// the manifest.
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.context.checksums = checksums;
+        self
+    }
+
+    /// Writes a manifest listing every output file to `path` when the writer is finished.
+    ///
+    /// The manifest is a CSV file with one row for each completed file, in the order they were
+    /// completed, under the header `key,sequence,path,rows,bytes,sha256,header`. `rows` doesn't count
+    /// the header, and `bytes` is the size of the file as stored, after any compression added by
+    /// [`ShardedWriter::on_create_file`]. `header` is the
+    /// header row as written to each file, which is empty if there is none, and `sha256` is
+    /// empty unless [`ShardedWriter::with_checksums`] is enabled. Like the completion
+    /// callback, the manifest lists files with their final paths.
+    ///
+    /// The manifest is only written by [`ShardedWriter::finish`], not when the writer is
+    /// dropped.
+    pub fn with_manifest<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.manifest = Some(path.into());
+        self.context.completed_files.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Saves progress to a checkpoint file at `path` every `every` input rows, so that a run
+    /// interrupted part way through an input file can be picked up again with
+    /// [`ShardedWriter::resume_file`].
+    ///
+    /// Checkpoints are only written by [`ShardedWriter::process_file`] and
+    /// [`ShardedWriter::resume_file`], since other inputs can't be reopened and seeked. Each
+    /// appends to the checkpoint file the position in the input, the running totals, and the
+    /// sequence number and length of the open file of every shard written to since the last
+    /// checkpoint, which are flushed first. Output files are added to it as they're created, so
+    /// a checkpoint's cost doesn't grow with the number of files written. A crash while writing
+    /// a checkpoint leaves the previous one in effect. Resuming rewrites the file with just the
+    /// latest state, and it's deleted by [`ShardedWriter::finish`] once every output file has
+    /// been completed.
+    ///
+    /// Resuming cuts each open output file back to what had been flushed at the checkpoint and
+    /// reopens it with [`OpenMode::Append`], so a writer given to
+    /// [`ShardedWriter::on_create_file`] must write output that can be appended to in that way:
+    /// anything it writes at the start of a file, such as a header, should only be written for
+    /// [`OpenMode::Create`]. Compressors generally can't be resumed; a gzip stream, for example,
+    /// would be left without its trailer, followed by a second stream.
+    pub fn with_checkpoint<P: Into<PathBuf>>(mut self, path: P, every: usize) -> Self {
+        self.checkpoint = Some(Checkpointing {
+            path: path.into(),
+            every: every.max(1),
+            input: None,
+            since_last: 0,
+            done: Vec::new(),
+            resume_at: None,
+        });
+        self
+    }
+
+    /// Specifies whether output paths that differ only in case, such as `Foo-0.csv` and
+    /// `foo-0.csv`, refer to the same file.
+    ///
+    /// Every output path is remembered, and creating a second file with a path that's already
+    /// been used fails with [`Error::PathCollision`] rather than overwriting the first file.
+    /// With [`ExistingFilePolicy::NextSequence`], a used path is skipped over just like an
+    /// existing file.
+    /// By default, paths are compared case-insensitively on Windows and macOS, whose
+    /// filesystems usually are, and case-sensitively elsewhere.
+    pub fn with_case_insensitive_paths(mut self, case_insensitive: bool) -> Self {
+        self.context.case_insensitive_paths = case_insensitive;
+        self
+    }
+
+    /// Limits the number of output files that may be open at the same time.
+    ///
+    /// When a record must be written to a shard whose file isn't open and `max_open_files` are
+    /// already open, the least-recently-written file is flushed and closed. It will be reopened
+    /// in [`OpenMode::Append`] the next time a record is written to it; the header is not
+    /// written again, and file splitting continues to count rows or bytes as if the file had
+    /// never been closed. A limit of zero is treated as one.
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = Some(max_open_files.max(1));
+        self
+    }
+
+    /// Specifies what happens to input rows that can't be parsed or for which the key selector
+    /// fails. The default is [`MalformedRowPolicy::FailFast`].
+    ///
+    /// This applies to [`ShardedWriter::process_file`], [`ShardedWriter::process_csv`],
+    /// [`ShardedWriter::process_reader`] and [`ShardedWriter::process_files_parallel`]. Records
+    /// passed to [`ShardedWriter::process_iter`] have already been parsed.
+    pub fn with_malformed_rows(mut self, policy: MalformedRowPolicy) -> Self {
+        self.malformed_rows = policy;
         self
     }
 
     /// Sets an optional function that will be called when individual files are completed, either
     /// because they have been split by the number of rows or bytes or because processing is
     /// complete and the values are being dropped.
-    pub fn on_file_completion(mut self, f: fn(&Path, &str)) -> Self {
-        self.on_file_completion = Some(f);
+    ///
+    /// The function is given a [FileCompleted] describing the file, including its path, shard
+    /// key, sequence number, the number of rows and bytes written, why it was completed and,
+    /// if [`ShardedWriter::with_checksums`] is enabled, its SHA-256 checksum.
+    ///
+    /// The closure may capture its environment, eg, a channel to send completed paths over or a
+    /// client to upload them with. It must be [Send] so the writer can be moved to another thread,
+    /// even if yours never is; state shared with the rest of a single-threaded program belongs in
+    /// an `Arc<Mutex<_>>` rather than an `Rc<RefCell<_>>`.
+    pub fn on_file_completion<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&FileCompleted<FKey::Key>) + Send + 'static,
+    {
+        self.context.on_file_completion = Some(Box::new(f));
         self
     }
 
-    /// Takes a closure that specifies how to create output files.
+    /// Takes a closure that specifies how to write to output files.
     ///
-    /// The closure provides the [Path] of the output file to be created. If you don't
-    /// provide your own way to create output files, the default implementation will simply create
-    /// a new [BufWriter] for the output file, which is the same as:
+    /// Each output file is opened by the writer, which passes the closure the [Path] of the
+    /// file, the [OpenMode] it was opened with, and a [std::io::BufWriter] for it. The closure
+    /// returns the writer that the CSV data will be written to, usually wrapping the one it was
+    /// given. Files are only opened with [`OpenMode::Append`] when they are reopened after being
+    /// closed to stay within [`ShardedWriter::with_max_open_files`], or when appending to
+    /// existing files with [`ExistingFilePolicy::Append`]. If you don't provide your own closure,
+    /// the file's writer is used as it is, which is the same as:
     ///
-    /// ```
-    /// my_sharded_writer.on_create_file(|path| Ok(BufWriter::new(File::create(path)?)));
+    /// ```ignore
+    /// my_sharded_writer.on_create_file(|_path, _mode, file| Ok(file));
     /// ```
     ///
     /// This function may be useful if, for example, you want to inject gzip compression into the
-    /// output writer.
-    pub fn on_create_file(mut self, f: fn(&Path) -> std::io::Result<Box<dyn Write>>) -> Self {
-        self.create_file_writer = f;
+    /// output writer. Because the writer the closure is given writes straight to the file, the
+    /// sizes and checksums reported for each file are of the compressed data as it's stored.
+    /// Compressed output can't be resumed from a checkpoint, though; see
+    /// [`ShardedWriter::with_checkpoint`].
+    ///
+    /// As with [`ShardedWriter::on_file_completion`], the closure may capture its environment,
+    /// such as a compression level. Both the closure and the writers it returns must be [Send];
+    /// a writer that isn't, say one holding an `Rc`, can't be used as an output file's writer.
+    pub fn on_create_file<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Path, OpenMode, Box<dyn Write + Send>) -> std::io::Result<Box<dyn Write + Send>>
+            + Send
+            + 'static,
+    {
+        self.context.create_file_writer = Box::new(f);
         self
     }
 
@@ -199,102 +650,1795 @@ where
     /// selector.
     ///
     /// This function will fail if the output directory or an output file can't be created or if a
-    /// row can't be written. It can also fail if it is called multiple times with files that have
-    /// different column counts.
+    /// row can't be written. Rows that can't be parsed, such as those with a different number of
+    /// columns than the first row, are handled according to the [MalformedRowPolicy].
+    ///
+    /// On success, the number of records written and skipped is returned.
+    pub fn process_file(&mut self, filename: &str) -> Result<ProcessSummary, Error> {
+        let mut reader = open_input(&self.reader_builder, filename, self.quarantines())?;
+
+        self.process_resumable(filename, &mut reader)
+    }
+
+    /// Processes an input file that may be checkpointed, from wherever `reader` is positioned.
+    fn process_resumable(
+        &mut self,
+        filename: &str,
+        reader: &mut csv::Reader<RawInput<std::fs::File>>,
+    ) -> Result<ProcessSummary, Error> {
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.input = Some(filename.to_owned());
+            checkpoint.since_last = 0;
+
+            if self.context.checkpoint.is_none() {
+                // Starting afresh replaces whatever checkpoint an earlier run left behind.
+                let file = std::fs::File::create(&checkpoint.path)?;
+                self.context.checkpoint = Some(checkpoint_writer(file));
+            }
+        }
+
+        let result = self.process_rows(input::raw_rows(reader));
+
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.input = None;
+        }
+        if result.is_ok() {
+            // A run resumed from here moves straight on to the next input.
+            self.write_checkpoint(filename, None, ProcessSummary::default())?;
+        }
+        result
+    }
+
+    /// Processes many input files concurrently, creating output files according to the specified
+    /// key selector.
+    ///
+    /// Up to `threads` worker threads each open and parse input files, handing batches of
+    /// records back to the calling thread, which selects keys and writes every record just as
+    /// [`ShardedWriter::process_file`] would. The same keys therefore produce the same output
+    /// files as processing each file in turn, but records from different inputs are interleaved
+    /// in no particular order, so with [FileSplitting] the rows that land in each sequence
+    /// number may differ between runs.
     ///
-    /// On success, the number of records written is returned.
-    pub fn process_file(&mut self, filename: &str) -> Result<usize, Error> {
-        let mut reader = csv::ReaderBuilder::new()
-            .delimiter(self.output_delimiter)
-            .has_headers(self.header_record.is_some())
-            .from_path(filename)?;
+    /// This function fails if any input file can't be opened or read, or if a malformed row is
+    /// found under [`MalformedRowPolicy::FailFast`], in which case the remaining inputs are
+    /// abandoned. Those errors are wrapped in an [`Error::Input`] naming the file they came from.
+    /// On success, the number of records written and skipped is returned.
+    pub fn process_files_parallel<P>(
+        &mut self,
+        filenames: &[P],
+        threads: usize,
+    ) -> Result<ProcessSummary, Error>
+    where
+        P: AsRef<Path> + Sync,
+    {
+        // The workers borrow the input dialect while `self` is busy writing, so lend it to them
+        // for the duration and put it back afterwards.
+        let has_headers = self.context.header_record.is_some();
+        let reader_builder =
+            std::mem::replace(&mut self.reader_builder, input_builder(has_headers));
+        let capture = self.quarantines();
+        let next_file = atomic::AtomicUsize::new(0);
+        let threads = threads.clamp(1, filenames.len().max(1));
+
+        let result = std::thread::scope(|scope| {
+            // Each batch is sent with the index of the file it came from, so errors can name it.
+            let (tx, rx) = mpsc::sync_channel::<(usize, Result<Vec<InputRow>, Error>)>(threads * 2);
+
+            for _ in 0..threads {
+                let tx = tx.clone();
+                let next_file = &next_file;
+                let reader_builder = &reader_builder;
+                scope.spawn(move || {
+                    loop {
+                        let index = next_file.fetch_add(1, atomic::Ordering::Relaxed);
+                        let Some(filename) = filenames.get(index) else {
+                            return;
+                        };
+                        let reader = open_input(reader_builder, filename, capture);
+
+                        let mut reader = match reader {
+                            Ok(reader) => reader,
+                            Err(e) => {
+                                tx.send((index, Err(e.into()))).ok();
+                                return;
+                            }
+                        };
 
-        let records = reader.records().filter_map(|r| r.ok());
-        self.process_iter(records)
+                        let mut batch = Vec::with_capacity(PARALLEL_BATCH_SIZE);
+                        for row in input::raw_rows(&mut reader) {
+                            let row = match row {
+                                Ok(row) => row,
+                                Err(e) => {
+                                    tx.send((index, Err(e))).ok();
+                                    return;
+                                }
+                            };
+
+                            batch.push(row);
+                            if batch.len() == PARALLEL_BATCH_SIZE {
+                                let full = std::mem::replace(
+                                    &mut batch,
+                                    Vec::with_capacity(PARALLEL_BATCH_SIZE),
+                                );
+                                if tx.send((index, Ok(full))).is_err() {
+                                    // The writer has given up; stop parsing.
+                                    return;
+                                }
+                            }
+                        }
+
+                        if !batch.is_empty() && tx.send((index, Ok(batch))).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+
+            // Only the workers hold senders now, so the loop below ends once they're all done.
+            drop(tx);
+
+            let mut summary = ProcessSummary::default();
+            for (index, batch) in rx.iter() {
+                let input_error = |source| Error::Input {
+                    path: filenames[index].as_ref().to_path_buf(),
+                    source: Box::new(source),
+                };
+
+                // Returning early drops the receiver, which stops the workers at their next send.
+                let batch = batch.map_err(input_error)?;
+                summary += self
+                    .process_rows(batch.into_iter().map(Ok))
+                    .map_err(|e| match e {
+                        Error::MalformedRow { .. } | Error::KeySelector { .. } => input_error(e),
+                        e => e,
+                    })?;
+            }
+
+            Ok(summary)
+        });
+
+        self.reader_builder = reader_builder;
+        result
     }
 
     /// Processes the input reader, creating output files as appropriate.
     ///
     /// This function will fail if the output directory or an output file can't be created or if a
-    /// row can't be written. It can also fail if it is called multiple times with files that have
-    /// different column counts.
+    /// row can't be written. Rows that can't be parsed, such as those with a different number of
+    /// columns than the first row, are handled according to the [MalformedRowPolicy].
     ///
-    /// On success, the number of records written is returned.
+    /// On success, the number of records written and skipped is returned.
     pub fn process_csv<T: std::io::Read>(
         &mut self,
         csv_reader: &mut csv::Reader<T>,
-    ) -> Result<usize, Error> {
-        let records = csv_reader.records().filter_map(|r| r.ok());
-
-        self.process_iter(records)
+    ) -> Result<ProcessSummary, Error> {
+        self.process_rows(input::rows(csv_reader))
     }
 
     /// Processes an iterator of [std::io::Read], creating output files as appropriate.
-    pub fn process_reader(&mut self, reader: impl std::io::Read) -> Result<usize, Error> {
-        let mut reader = csv::ReaderBuilder::new()
-            .delimiter(self.output_delimiter)
-            .has_headers(self.header_record.is_some())
-            .from_reader(reader);
+    pub fn process_reader(&mut self, reader: impl std::io::Read) -> Result<ProcessSummary, Error> {
+        let capture = self.quarantines();
+        let mut reader = self
+            .reader_builder
+            .from_reader(RawInput::new(reader, capture));
 
-        let records = reader.records().filter_map(|r| r.ok());
-
-        self.process_iter(records)
+        self.process_rows(input::raw_rows(&mut reader))
     }
 
     /// Iterates over every record, calculating the shard key for each, getting or creating the shard file,
     /// and writing the record.
-    pub fn process_iter<T>(&mut self, records: T) -> Result<usize, Error>
+    pub fn process_iter<T>(&mut self, records: T) -> Result<ProcessSummary, Error>
     where
         T: IntoIterator<Item = StringRecord>,
     {
-        let mut records_written = 0;
-        for record in records {
-            let key = (self.key_selector)(&record);
+        self.process_rows(
+            records
+                .into_iter()
+                .map(|record| Ok(InputRow::Record { record, raw: None })),
+        )
+    }
+
+    /// Writes every valid row and applies the [MalformedRowPolicy] to the rest.
+    fn process_rows<T>(&mut self, rows: T) -> Result<ProcessSummary, Error>
+    where
+        T: IntoIterator<Item = Result<InputRow, Error>>,
+    {
+        let mut summary = ProcessSummary::default();
+        let result = self.process_rows_into(rows, &mut summary);
+
+        // Rows processed before any error still count towards the totals.
+        self.totals += summary;
+        result.map(|()| summary)
+    }
 
-            match self.handles.entry(key.clone()) {
-                Entry::Occupied(mut e) => {
-                    e.get_mut().write_record(&record)?;
+    fn process_rows_into<T>(&mut self, rows: T, summary: &mut ProcessSummary) -> Result<(), Error>
+    where
+        T: IntoIterator<Item = Result<InputRow, Error>>,
+    {
+        for row in rows {
+            let row = row?;
+            self.maybe_checkpoint(&row, *summary)?;
+
+            match row {
+                InputRow::Record { record, raw } => {
+                    let mut keys = std::mem::take(&mut self.keys);
+                    keys.clear();
+
+                    if let Err(error) = self.key_selector.select_keys(&record, &mut keys) {
+                        let line = record.position().map_or(0, |p| p.line());
+                        if !self.skip_row(line, &error, record.as_byte_record(), raw)? {
+                            let processed = self.totals.records_read
+                                + self.totals.records_skipped
+                                + summary.records_read
+                                + summary.records_skipped;
+                            return Err(Error::KeySelector {
+                                error,
+                                index: processed,
+                                position: record.position().cloned(),
+                                record,
+                            });
+                        }
+
+                        summary.records_skipped += 1;
+                        keys.clear();
+                    } else {
+                        summary.records_read += 1;
+                        if keys.is_empty() {
+                            summary.records_filtered += 1;
+                        }
+
+                        for key in keys.drain(..) {
+                            self.write_to_shard(key, &record)?;
+                            summary.records_written += 1;
+                        }
+                    }
+
+                    self.keys = keys;
                 }
-                Entry::Vacant(e) => {
-                    let header_record = self.header_record.clone();
-                    let create_output_filename = self.create_output_filename.clone();
-                    let mut shard = shard::Shard::new(
-                        self.output_splitting,
-                        key,
-                        header_record,
-                        self.create_file_writer,
-                        create_output_filename,
-                        self.on_file_completion,
-                    );
+                InputRow::Malformed {
+                    line,
+                    error,
+                    record,
+                    raw,
+                } => {
+                    if !self.skip_row(line, &error, &record, raw)? {
+                        return Err(Error::MalformedRow { line, error });
+                    }
 
-                    shard.write_record(&record)?;
-                    e.insert(shard);
+                    summary.records_skipped += 1;
                 }
-            };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether rows may be written to a dead-letter file, in which case inputs keep their bytes.
+    fn quarantines(&self) -> bool {
+        matches!(self.malformed_rows, MalformedRowPolicy::Quarantine(_))
+    }
+
+    /// Skips or quarantines a row that couldn't be parsed or assigned a key, according to the
+    /// [MalformedRowPolicy].
+    ///
+    /// Returns `false` if the policy is to fail fast, in which case the caller should stop.
+    fn skip_row(
+        &mut self,
+        line: u64,
+        error: &dyn std::fmt::Display,
+        record: &csv::ByteRecord,
+        raw: Option<Vec<u8>>,
+    ) -> Result<bool, Error> {
+        match &self.malformed_rows {
+            MalformedRowPolicy::FailFast => Ok(false),
+            MalformedRowPolicy::Skip => Ok(true),
+            MalformedRowPolicy::Quarantine(path) => {
+                let dead_letter = match &mut self.dead_letter {
+                    Some(w) => w,
+                    None => self.dead_letter.insert(csv::Writer::from_path(path)?),
+                };
+
+                // Rows that weren't read through a `RawInput` are written back out as CSV.
+                let raw = match raw {
+                    Some(raw) => raw,
+                    None => {
+                        let mut row = csv::Writer::from_writer(Vec::new());
+                        row.write_byte_record(record)?;
+                        let mut row = row.into_inner().map_err(|e| e.into_error())?;
+                        row.pop(); // the line terminator
+                        row
+                    }
+                };
+
+                let line = line.to_string();
+                let error = error.to_string();
+                dead_letter.write_record([line.as_bytes(), error.as_bytes(), &raw])?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Writes a checkpoint if one is due before `row` is processed. `summary` holds what's been
+    /// processed by the current call so far.
+    fn maybe_checkpoint(&mut self, row: &InputRow, summary: ProcessSummary) -> Result<(), Error> {
+        let Some(checkpoint) = self.checkpoint.as_mut() else {
+            return Ok(());
+        };
+        let Some(input) = checkpoint.input.clone() else {
+            return Ok(());
+        };
+
+        checkpoint.since_last += 1;
+        if checkpoint.since_last <= checkpoint.every {
+            return Ok(());
+        }
+        checkpoint.since_last = 1;
+
+        let position = match row {
+            InputRow::Record { record, .. } => record.position(),
+            InputRow::Malformed { record, .. } => record.position(),
+        };
+        match position {
+            Some(position) => self.write_checkpoint(&input, Some(position), summary),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a checkpoint from which processing `input` can resume at `position`, or, if
+    /// there's no position, from which the next input can be processed once `input` is done.
+    ///
+    /// The checkpoint file is a headerless CSV file that's only ever appended to, whose first
+    /// field identifies each row. Output files are recorded as they're `created` and, if
+    /// there's to be a manifest, `completed`. Each checkpoint then adds the `totals` so far,
+    /// the length of the `dead_letter` file, a row for each `shard` written since the last
+    /// checkpoint, the `input` file and position or that the input is `done`, and finally an
+    /// `end` row. A checkpoint costs the same however many files have been written, and one cut
+    /// short by a crash lacks its `end` row, so it's ignored.
+    fn write_checkpoint(
+        &mut self,
+        input: &str,
+        position: Option<&csv::Position>,
+        summary: ProcessSummary,
+    ) -> Result<(), Error> {
+        let Some(out) = self.context.checkpoint.as_mut() else {
+            return Ok(());
+        };
+
+        let mut totals = self.totals;
+        totals += summary;
+        out.write_record([
+            "totals".to_owned(),
+            totals.records_read.to_string(),
+            totals.records_written.to_string(),
+            totals.records_skipped.to_string(),
+            totals.records_filtered.to_string(),
+        ])?;
+
+        if let (Some(dead_letter), MalformedRowPolicy::Quarantine(dead_letter_path)) =
+            (self.dead_letter.as_mut(), &self.malformed_rows)
+        {
+            dead_letter.flush()?;
+            let len = std::fs::metadata(dead_letter_path)?.len();
+            out.write_record(["dead_letter", &len.to_string()])?;
+        }
+
+        for shard in self.handles.values_mut() {
+            shard.save(out)?;
+        }
+
+        match position {
+            Some(position) => out.write_record([
+                "input",
+                input,
+                &position.byte().to_string(),
+                &position.line().to_string(),
+                &position.record().to_string(),
+            ])?,
+            None => out.write_record(["done", input])?,
+        }
+        out.write_record(["end"])?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes `record` to the shard for `key`, creating the shard if necessary and closing the
+    /// least-recently-used file if opening another would exceed `max_open_files`.
+    fn write_to_shard(&mut self, key: FKey::Key, record: &StringRecord) -> Result<(), Error> {
+        self.clock += 1;
+
+        let (was_open, last_used) = match self.handles.get(&key) {
+            Some(shard) => (shard.is_open(), shard.last_used()),
+            None => (false, 0),
+        };
+
+        if let Some(max_open_files) = self.max_open_files {
+            if was_open {
+                self.open_shards.remove(&last_used);
+            } else {
+                while self.open_shards.len() >= max_open_files {
+                    let Some((_, lru_key)) = self.open_shards.pop_first() else {
+                        break;
+                    };
+                    if let Some(lru_shard) = self.handles.get_mut(&lru_key) {
+                        lru_shard.close_handle()?;
+                    }
+                }
+            }
+        }
+
+        let shard = match self.handles.entry(key) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let shard = shard::Shard::new(e.key().clone());
+                e.insert(shard)
+            }
+        };
+
+        shard.write_record(record, &mut self.context)?;
+        shard.set_last_used(self.clock);
+
+        if self.max_open_files.is_some() && shard.is_open() {
+            self.open_shards.insert(self.clock, shard.key().clone());
+        }
+
+        Ok(())
+    }
+
+    /// Flushes and closes every open output file, calling the file completion callback for each,
+    /// and returns a summary of everything written.
+    ///
+    /// Unlike simply dropping the writer, this reports failures: every file that couldn't be
+    /// flushed is returned in [`Error::Close`] along with its path and shard key. Files that
+    /// closed successfully still have their completion callback called.
+    pub fn finish(mut self) -> Result<RunSummary, Error> {
+        let mut summary = RunSummary {
+            records_read: self.totals.records_read,
+            records_written: self.totals.records_written,
+            records_skipped: self.totals.records_skipped,
+            records_filtered: self.totals.records_filtered,
+            shards: self.handles.len(),
+            files_written: 0,
+        };
+
+        let mut errors = Vec::new();
+        for (_, mut shard) in self.handles.drain() {
+            if let Err(e) = shard.complete_file(&mut self.context, CompletionReason::Finished) {
+                errors.push(e);
+            }
+            summary.files_written += shard.files_written();
+        }
+
+        if let Some(mut dead_letter) = self.dead_letter.take() {
+            if let Err(error) = dead_letter.flush() {
+                if let MalformedRowPolicy::Quarantine(path) = &self.malformed_rows {
+                    errors.push(FileError {
+                        path: path.clone(),
+                        key: String::new(),
+                        error,
+                    });
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::Close(errors));
+        }
+
+        if let Some(path) = &self.manifest {
+            self.write_manifest(path)?;
+        }
+
+        if let Some(checkpoint) = &self.checkpoint {
+            self.context.checkpoint = None;
+            match std::fs::remove_file(&checkpoint.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Writes the manifest of every completed file to `path`.
+    fn write_manifest(&self, path: &Path) -> Result<(), Error> {
+        let header = match &self.context.header_record {
+            Some(header) => {
+                let mut w = self.context.writer_builder.from_writer(Vec::new());
+                w.write_record(header)?;
+                let mut line = w.into_inner().map_err(|e| e.into_error())?;
+                while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+                    line.pop();
+                }
+                String::from_utf8_lossy(&line).into_owned()
+            }
+            None => String::new(),
+        };
 
-            records_written += 1;
+        let temp_path = shard::temp_path(path);
+        let mut out = csv::Writer::from_path(&temp_path)?;
+        out.write_record([
+            "key", "sequence", "path", "rows", "bytes", "sha256", "header",
+        ])?;
+        for completed in self.context.completed_files.iter().flatten() {
+            let mut row = completed.fields().to_vec();
+            row.push(header.clone());
+            out.write_record(&row)?;
         }
 
-        Ok(records_written)
+        out.flush()?;
+        drop(out);
+        std::fs::rename(temp_path, path)?;
+        Ok(())
     }
 
     /// Checks if `key` has been seen in the processed data.
-    pub fn is_shard_key_seen(&self, key: &str) -> bool {
+    ///
+    /// As with [HashMap::contains_key], any borrowed form of the key may be used, eg, a `&str`
+    /// for `String` keys.
+    pub fn is_shard_key_seen<Q>(&self, key: &Q) -> bool
+    where
+        FKey::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         self.handles.contains_key(key)
     }
 
     /// Returns a vec of all keys that have been seen.
-    pub fn shard_keys_seen(&self) -> Vec<String> {
+    pub fn shard_keys_seen(&self) -> Vec<FKey::Key> {
         self.handles.keys().cloned().collect()
     }
 }
 
-/// The standard approach to creating a file writer -- create and buffer it.
+impl<FKey, FNameFile> ShardedWriter<FKey, FNameFile>
+where
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display + FromStr,
+    FNameFile: ShardNaming<FKey::Key>,
+{
+    /// Processes an input file, picking up where an interrupted run left off if there's a
+    /// checkpoint for it.
+    ///
+    /// This requires [`ShardedWriter::with_checkpoint`]. If the checkpoint file exists, every
+    /// shard's sequence number and split counter are restored, each open output file is
+    /// truncated to the length it had at the checkpoint, and processing continues from the
+    /// saved input position, recreating any output written after the checkpoint. Otherwise, this
+    /// is the same as [`ShardedWriter::process_file`], so the same call can be used both to
+    /// start a run and to resume it.
+    ///
+    /// Shard keys are recreated from their [Display] form with [FromStr], so the two must
+    /// round-trip. Files completed after the checkpoint are completed again, so the completion
+    /// callback may be called more than once for them. The checkpoint lists every file the
+    /// interrupted run created, including those created after the checkpoint was written, and
+    /// only those are overwritten or truncated back to what they held before the run appended to
+    /// them; the [ExistingFilePolicy] applies to any other file in the way. Resuming must be the
+    /// first thing done with the writer.
+    ///
+    /// A run over several input files is resumed by calling this for each of them again, as the
+    /// interrupted run did. Inputs that the interrupted run finished are skipped, returning an
+    /// empty summary, the one it was part way through continues from the checkpoint, and any
+    /// others are processed from the start.
+    ///
+    /// The returned summary covers only the rows processed by this call, but the totals given
+    /// by [`ShardedWriter::finish`] include the rows processed before the checkpoint.
+    pub fn resume_file(&mut self, filename: &str) -> Result<ProcessSummary, Error> {
+        let Some(checkpoint) = &self.checkpoint else {
+            return Err(Error::Checkpoint("no checkpoint path was set".to_owned()));
+        };
+
+        // The checkpoint is only read by the first call; after that, this writer is the run.
+        if self.context.checkpoint.is_none() && checkpoint.path.exists() {
+            if !self.handles.is_empty() {
+                return Err(Error::Checkpoint(
+                    "the writer has already written records".to_owned(),
+                ));
+            }
+            self.restore_checkpoint()?;
+        }
+
+        let mut position = None;
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            if let Some(i) = checkpoint.done.iter().position(|done| done == filename) {
+                checkpoint.done.remove(i);
+                return Ok(ProcessSummary::default());
+            }
+
+            match checkpoint.resume_at.take() {
+                Some((input, p)) if input == filename => position = Some(p),
+                Some((input, p)) => {
+                    let error = format!(
+                        "the interrupted run was part way through '{input}', not '{filename}'"
+                    );
+                    checkpoint.resume_at = Some((input, p));
+                    return Err(Error::Checkpoint(error));
+                }
+                None => {}
+            }
+        }
+
+        // An input that the interrupted run hadn't checkpointed in yet is processed from the
+        // start.
+        let mut reader = open_input(&self.reader_builder, filename, self.quarantines())?;
+        if let Some(position) = position {
+            reader.seek(position)?;
+        }
+
+        self.process_resumable(filename, &mut reader)
+    }
+
+    /// Restores the state of an interrupted run from its checkpoint file, which is then
+    /// rewritten with just that state and reopened for appending.
+    fn restore_checkpoint(&mut self) -> Result<(), Error> {
+        let Some(checkpoint) = &self.checkpoint else {
+            return Ok(());
+        };
+        let path = checkpoint.path.clone();
+        let rows = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&path)?
+            .into_records()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Rows after the last `end` are from a checkpoint cut short, which is ignored, or
+        // record files created since the last checkpoint, which the resumed run will rewrite.
+        let committed = rows
+            .iter()
+            .rposition(|row| row.get(0) == Some("end"))
+            .map_or(0, |i| i + 1);
+        let (committed, uncommitted) = rows.split_at(committed);
+
+        // The checkpoint is rewritten with just what's still needed: the files and inputs
+        // recorded so far and the latest of everything else.
+        let mut done = Vec::new();
+        let mut files = Vec::new();
+        let mut latest = HashMap::new();
+        let mut shards = BTreeMap::new();
+        for row in committed {
+            match row.get(0) {
+                Some("created") => {
+                    let path: String = shard::checkpoint_field(row, 1)?;
+                    let key = shard::checkpoint_field(row, 3)?;
+                    let sequence = shard::checkpoint_field(row, 4)?;
+                    self.context.restore_claim(Path::new(&path), key, sequence);
+                    files.push(row);
+                }
+                Some("completed") => {
+                    if let Some(completed_files) = self.context.completed_files.as_mut() {
+                        completed_files.push(shard::CompletedFile::from_fields(row, 1)?);
+                    }
+                    files.push(row);
+                }
+                Some("shard") => {
+                    shards.insert(shard::checkpoint_field::<String>(row, 1)?, row);
+                }
+                Some("done") => {
+                    done.push(shard::checkpoint_field(row, 1)?);
+                    latest.remove("input");
+                    files.push(row);
+                }
+                Some(kind @ ("totals" | "dead_letter" | "input")) => {
+                    latest.insert(kind, row);
+                }
+                Some("end") => {}
+                _ => return Err(Error::Checkpoint(format!("unexpected row {row:?}"))),
+            }
+        }
+
+        let mut resume_at = None;
+        if let Some(row) = latest.get("input") {
+            let mut position = csv::Position::new();
+            position
+                .set_byte(shard::checkpoint_field(row, 2)?)
+                .set_line(shard::checkpoint_field(row, 3)?)
+                .set_record(shard::checkpoint_field(row, 4)?);
+            resume_at = Some((shard::checkpoint_field(row, 1)?, position));
+        }
+
+        if let Some(row) = latest.get("totals") {
+            self.totals = ProcessSummary {
+                records_read: shard::checkpoint_field(row, 1)?,
+                records_written: shard::checkpoint_field(row, 2)?,
+                records_skipped: shard::checkpoint_field(row, 3)?,
+                records_filtered: shard::checkpoint_field(row, 4)?,
+            };
+        }
+
+        if let (Some(row), MalformedRowPolicy::Quarantine(path)) =
+            (latest.get("dead_letter"), &self.malformed_rows)
+        {
+            let file = std::fs::OpenOptions::new().append(true).open(path)?;
+            file.set_len(shard::checkpoint_field(row, 1)?)?;
+            self.dead_letter = Some(csv::Writer::from_writer(file));
+        }
+
+        for (key, row) in &shards {
+            let key: FKey::Key = key
+                .parse()
+                .map_err(|_| Error::Checkpoint(format!("can't parse shard key '{key}'")))?;
+            let shard = shard::Shard::restore(key.clone(), row, &mut self.context)?;
+            self.handles.insert(key, shard);
+        }
+
+        for row in uncommitted {
+            if row.get(0) == Some("created") {
+                let path: String = shard::checkpoint_field(row, 1)?;
+                let original_len = match row.get(2) {
+                    Some("") | None => None,
+                    Some(_) => Some(shard::checkpoint_field(row, 2)?),
+                };
+                self.context
+                    .resumable_files
+                    .entry(path.into())
+                    .or_insert(original_len);
+            }
+        }
+
+        let temp_path = shard::temp_path(&path);
+        let mut out = checkpoint_writer(std::fs::File::create(&temp_path)?);
+        if !committed.is_empty() {
+            let last = |kind| latest.get(kind).copied();
+            let rows = files
+                .into_iter()
+                .chain(last("totals"))
+                .chain(last("dead_letter"))
+                .chain(shards.into_values())
+                .chain(last("input"));
+            for row in rows {
+                out.write_record(row)?;
+            }
+            out.write_record(["end"])?;
+        }
+        for row in uncommitted
+            .iter()
+            .filter(|row| row.get(0) == Some("created"))
+        {
+            out.write_record(row)?;
+        }
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&temp_path, &path)?;
+
+        let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        self.context.checkpoint = Some(checkpoint_writer(file));
+
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.done = done;
+            checkpoint.resume_at = resume_at;
+        }
+        Ok(())
+    }
+}
+
+// A `ShardedWriter` is `Send` whenever its key selector and file naming closures are.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<ShardedWriter<fn(&StringRecord) -> String, fn(&String, usize) -> String>>();
+};
+
+impl<FKey, FNameFile> Drop for ShardedWriter<FKey, FNameFile>
+where
+    FKey: KeySelector,
+    FKey::Key: Hash + Eq + Clone + Display,
+    FNameFile: ShardNaming<FKey::Key>,
+{
+    fn drop(&mut self) {
+        // Errors can't be reported from here; callers who care should use `ShardedWriter::finish`.
+        for shard in self.handles.values_mut() {
+            if self.context.atomic_output {
+                // Being dropped without being finished may mean the run failed, so don't publish
+                // files that may be incomplete; leave them under their temporary names.
+                shard.close_handle().ok();
+            } else {
+                shard
+                    .complete_file(&mut self.context, CompletionReason::Finished)
+                    .ok();
+            }
+        }
+    }
+}
+
+/// Creates the default input dialect for data with or without a header row.
+fn input_builder(has_headers: bool) -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.has_headers(has_headers);
+    builder
+}
+
+/// Opens an input file, capturing each row's bytes as read if they may need to be quarantined.
+fn open_input<P: AsRef<Path>>(
+    builder: &csv::ReaderBuilder,
+    path: P,
+    capture: bool,
+) -> Result<csv::Reader<RawInput<std::fs::File>>, csv::Error> {
+    let file = std::fs::File::open(path)?;
+    Ok(builder.from_reader(RawInput::new(file, capture)))
+}
+
+/// Creates the writer for a checkpoint file, whose rows vary in length.
+fn checkpoint_writer(file: std::fs::File) -> csv::Writer<std::fs::File> {
+    csv::WriterBuilder::new().flexible(true).from_writer(file)
+}
+
+/// The standard approach to writing a file -- through its buffered writer, unchanged.
 ///
 /// To do something different (such as gzipping output), [ShardedWriter::on_create_file]
 /// is passed an alternate function with this signature.
-fn default_create_file_writer(path: &Path) -> std::io::Result<Box<dyn Write>> {
-    let writer = std::fs::File::create(path)?;
-    let buf = BufWriter::new(writer);
-    Ok(Box::new(buf))
+fn default_create_file_writer(
+    _path: &Path,
+    _mode: OpenMode,
+    file: shard::FileWriter,
+) -> std::io::Result<shard::FileWriter> {
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::BTreeMap,
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    /// Creates an empty directory for a test's output.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shard-csv-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Reads every file under `dir`, keyed by its path relative to `dir`.
+    fn read_dir(dir: &Path) -> BTreeMap<PathBuf, String> {
+        fn visit(root: &Path, dir: &Path, files: &mut BTreeMap<PathBuf, String>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    visit(root, &path, files);
+                } else {
+                    let contents = fs::read_to_string(&path).unwrap();
+                    files.insert(path.strip_prefix(root).unwrap().to_owned(), contents);
+                }
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        visit(dir, dir, &mut files);
+        files
+    }
+
+    /// Builds `key,value` records from `key:value` pairs.
+    fn records(rows: &[&str]) -> Vec<StringRecord> {
+        rows.iter()
+            .map(|row| StringRecord::from(row.split(':').collect::<Vec<_>>()))
+            .collect()
+    }
+
+    #[test]
+    fn reopens_files_closed_to_limit_open_files() {
+        let dir = test_dir("lru");
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let log = opened.clone();
+
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(&dir)
+            .with_output_splitting(FileSplitting::SplitAfterRows(2))
+            .with_max_open_files(1)
+            .on_create_file(move |path, mode, file| {
+                log.lock().unwrap().push(mode);
+                default_create_file_writer(path, mode, file)
+            });
+
+        writer
+            .process_iter(records(&["a:1", "b:2", "a:3", "c:4", "b:5", "a:6", "b:7"]))
+            .unwrap();
+        let summary = writer.finish().unwrap();
+
+        assert_eq!(summary.files_written, 5);
+        let files = read_dir(&dir);
+        let expected = [
+            ("a-0.csv", "key,value\na,1\na,3\n"),
+            ("a-1.csv", "key,value\na,6\n"),
+            ("b-0.csv", "key,value\nb,2\nb,5\n"),
+            ("b-1.csv", "key,value\nb,7\n"),
+            ("c-0.csv", "key,value\nc,4\n"),
+        ];
+        assert_eq!(
+            files,
+            expected
+                .iter()
+                .map(|(path, contents)| (PathBuf::from(path), contents.to_string()))
+                .collect()
+        );
+
+        // Each shard's first file was closed by the other shards and reopened to append.
+        let opened = opened.lock().unwrap();
+        assert_eq!(opened.iter().filter(|m| **m == OpenMode::Create).count(), 5);
+        assert_eq!(opened.iter().filter(|m| **m == OpenMode::Append).count(), 2);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn key_selector_errors_index_rows_across_calls() {
+        let dir = test_dir("index");
+        let mut writer = ShardedWriterBuilder::new_without_header()
+            .with_try_key_selector(|rec: &StringRecord| match &rec[0] {
+                "bad" => Err("bad key"),
+                key => Ok(key.to_owned()),
+            })
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(&dir);
+
+        writer
+            .process_iter(records(&["a:1", "b:2", "a:3"]))
+            .unwrap();
+        match writer.process_iter(records(&["b:4", "bad:5"])) {
+            Err(Error::KeySelector { index, record, .. }) => {
+                assert_eq!(index, 4);
+                assert_eq!(&record[1], "5");
+            }
+            other => panic!("expected a key selector error, got {other:?}"),
+        }
+
+        drop(writer);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn atomic_append_leaves_the_original_until_complete() {
+        let dir = test_dir("atomic-append");
+        fs::write(dir.join("a-0.csv"), "key,value\na,0\n").unwrap();
+        let writer = || {
+            ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+                .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+                .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+                .with_output_directory(&dir)
+                .with_existing_files(ExistingFilePolicy::Append)
+                .with_atomic_output(true)
+        };
+
+        // A run that dies part-way through leaves the original file alone.
+        let mut crashed = writer();
+        crashed.process_iter(records(&["a:1", "a:2"])).unwrap();
+        std::mem::forget(crashed);
+        assert_eq!(
+            fs::read_to_string(dir.join("a-0.csv")).unwrap(),
+            "key,value\na,0\n"
+        );
+        assert!(dir.join(".a-0.csv.inprogress").exists());
+
+        let mut finished = writer();
+        finished.process_iter(records(&["a:1", "a:2"])).unwrap();
+        finished.finish().unwrap();
+        let files = read_dir(&dir);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[Path::new("a-0.csv")], "key,value\na,0\na,1\na,2\n");
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn atomic_output_is_only_published_when_finished() {
+        let dir = test_dir("atomic-drop");
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let log = completed.clone();
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(&dir)
+            .with_output_splitting(FileSplitting::SplitAfterRows(2))
+            .with_atomic_output(true)
+            .on_file_completion(move |file| log.lock().unwrap().push(file.path.to_owned()));
+
+        writer
+            .process_iter(records(&["a:1", "a:2", "a:3"]))
+            .unwrap();
+        drop(writer);
+
+        // The split file was published, but the one still being written when the writer was
+        // dropped wasn't.
+        let files = read_dir(&dir);
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [Path::new(".a-1.csv.inprogress"), Path::new("a-0.csv")]
+        );
+        assert_eq!(*completed.lock().unwrap(), [dir.join("a-0.csv")]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Writes `key:value` rows to a CSV file with a header, returning its path.
+    fn write_input(dir: &Path, rows: &[&str]) -> String {
+        let path = dir.join("input.csv");
+        let mut contents = String::from("key,value\n");
+        for row in rows {
+            contents.push_str(&row.replace(':', ","));
+            contents.push('\n');
+        }
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// A checkpointed writer for `key,value` input whose key selector fails on the row with the
+    /// value `crash_at`, leaving the run as a crash would once the writer is forgotten.
+    fn checkpointed_writer(
+        out: &Path,
+        checkpoint: &Path,
+        crash_at: Option<&'static str>,
+    ) -> ShardedWriter<impl KeySelector<Key = String>, impl ShardNaming<String>> {
+        ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_try_key_selector(move |rec: &StringRecord| match crash_at {
+                Some(value) if &rec[1] == value => Err("crash"),
+                _ => Ok(rec[0].to_owned()),
+            })
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(out)
+            .with_checkpoint(checkpoint, 3)
+    }
+
+    /// Processes `rows` without interruption, then again with a crash at the row with the value
+    /// `crash_at` followed by a resume, and checks that both produce the same files. `setup`
+    /// prepares each output directory beforehand.
+    fn check_resume<FKey, FNameFile>(
+        name: &str,
+        rows: &[&str],
+        crash_at: &'static str,
+        setup: impl Fn(&Path),
+        writer: impl Fn(&Path, &Path, Option<&'static str>) -> ShardedWriter<FKey, FNameFile>,
+    ) where
+        FKey: KeySelector,
+        FKey::Key: Hash + Eq + Clone + Display + FromStr,
+        FNameFile: ShardNaming<FKey::Key>,
+    {
+        let dir = test_dir(name);
+        let input = write_input(&dir, rows);
+        let checkpoint = dir.join("checkpoint.csv");
+
+        let expected_dir = dir.join("expected");
+        fs::create_dir_all(&expected_dir).unwrap();
+        setup(&expected_dir);
+        let mut expected = writer(&expected_dir, &checkpoint, None);
+        expected.process_file(&input).unwrap();
+        expected.finish().unwrap();
+
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        setup(&out);
+        let mut crashed = writer(&out, &checkpoint, Some(crash_at));
+        assert!(crashed.resume_file(&input).is_err());
+        std::mem::forget(crashed);
+        assert!(checkpoint.exists());
+
+        let mut resumed = writer(&out, &checkpoint, None);
+        resumed.resume_file(&input).unwrap();
+        resumed.finish().unwrap();
+
+        // A manifest names files by their paths, which differ only in the output directory, and
+        // lists the files completed by `finish` in no particular order.
+        let sort_manifest = |mut files: BTreeMap<PathBuf, String>| {
+            if let Some(manifest) = files.get_mut(Path::new("manifest.csv")) {
+                let mut lines: Vec<_> = manifest.lines().collect();
+                lines.sort();
+                *manifest = lines.join("\n");
+            }
+            files
+        };
+        let mut expected = read_dir(&expected_dir);
+        for contents in expected.values_mut() {
+            *contents = contents.replace(expected_dir.to_str().unwrap(), out.to_str().unwrap());
+        }
+        assert_eq!(sort_manifest(read_dir(&out)), sort_manifest(expected));
+        assert!(!checkpoint.exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    const ROWS: [&str; 12] = [
+        "a:1", "b:2", "a:3", "b:4", "a:5", "a:6", "z:7", "b:8", "z:9", "a:10", "z:11", "b:12",
+    ];
+
+    #[test]
+    fn resume_keeps_files_from_before_the_run_with_next_sequence() {
+        // `z` is first seen after the last checkpoint before the crash, when `z-0.csv` is
+        // skipped because it was already there.
+        check_resume(
+            "resume-next-sequence",
+            &ROWS,
+            "11",
+            |out| fs::write(out.join("z-0.csv"), "protected\n").unwrap(),
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_existing_files(ExistingFilePolicy::NextSequence)
+            },
+        );
+    }
+
+    #[test]
+    fn resume_appends_once_to_files_from_before_the_run() {
+        check_resume(
+            "resume-append",
+            &ROWS,
+            "11",
+            |out| fs::write(out.join("z-0.csv"), "key,value\nz,0\n").unwrap(),
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_existing_files(ExistingFilePolicy::Append)
+                    .with_max_open_files(1)
+            },
+        );
+    }
+
+    #[test]
+    fn resume_still_refuses_to_overwrite_other_files() {
+        let dir = test_dir("resume-error");
+        let input = write_input(&dir, &ROWS);
+        let checkpoint = dir.join("checkpoint.csv");
+        let out = dir.join("out");
+
+        // The run crashes just after a checkpoint, before `z-0.csv` would be created.
+        let mut crashed = checkpointed_writer(&out, &checkpoint, Some("7"))
+            .with_existing_files(ExistingFilePolicy::Error);
+        assert!(crashed.resume_file(&input).is_err());
+        std::mem::forget(crashed);
+
+        // The crashed run's own files are rewritten, but not one that appeared since.
+        fs::write(out.join("z-0.csv"), "protected\n").unwrap();
+        let mut resumed = checkpointed_writer(&out, &checkpoint, None)
+            .with_existing_files(ExistingFilePolicy::Error);
+        assert!(matches!(
+            resumed.resume_file(&input),
+            Err(Error::FileExists(path)) if path == out.join("z-0.csv")
+        ));
+        assert_eq!(
+            fs::read_to_string(out.join("z-0.csv")).unwrap(),
+            "protected\n"
+        );
+
+        drop(resumed);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Rows for which, splitting after two rows and checkpointing every three, `b-1.csv` is split
+    /// off between the last checkpoint and a crash at `12`.
+    const SPLIT_ROWS: [&str; 12] = [
+        "a:1", "b:2", "a:3", "b:4", "a:5", "z:6", "b:7", "z:8", "a:9", "b:10", "a:11", "z:12",
+    ];
+
+    #[test]
+    fn resume_after_a_split() {
+        check_resume(
+            "resume-split",
+            &SPLIT_ROWS,
+            "12",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+            },
+        );
+    }
+
+    #[test]
+    fn resume_lists_every_file_in_the_manifest() {
+        check_resume(
+            "resume-manifest",
+            &SPLIT_ROWS,
+            "12",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+                    .with_checksums(true)
+                    .with_manifest(out.join("manifest.csv"))
+            },
+        );
+    }
+
+    #[test]
+    fn checkpoints_only_record_what_changed() {
+        let dir = test_dir("checkpoint-changes");
+        let mut rows: Vec<String> = (0..20).map(|i| format!("k{i}:{i}")).collect();
+        rows.extend((20..50).map(|i| format!("a:{i}")));
+        let rows: Vec<&str> = rows.iter().map(String::as_str).collect();
+        let input = write_input(&dir, &rows);
+        let checkpoint = dir.join("checkpoint.csv");
+
+        let mut crashed = checkpointed_writer(&dir.join("out"), &checkpoint, Some("49"));
+        assert!(crashed.resume_file(&input).is_err());
+        std::mem::forget(crashed);
+
+        let rows = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&checkpoint)
+            .unwrap()
+            .into_records()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let kinds =
+            |rows: &[StringRecord], kind: &str| rows.iter().filter(|row| &row[0] == kind).count();
+
+        // Each file is recorded once, when it's created.
+        assert_eq!(kinds(&rows, "created"), 21);
+
+        // Each checkpoint saves only the shards written in the three rows since the one before,
+        // so once the 20 `k` keys are done with, that's just `a`. The last part of the split is
+        // what was recorded after the last checkpoint.
+        let checkpoints: Vec<_> = rows.split(|row| &row[0] == "end").collect();
+        assert_eq!(checkpoints.len(), 17);
+        assert!(checkpoints.iter().all(|rows| kinds(rows, "shard") <= 3));
+        for rows in &checkpoints[7..16] {
+            assert_eq!(kinds(rows, "shard"), 1, "{rows:?}");
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn resume_with_files_closed_to_limit_open_files() {
+        check_resume(
+            "resume-lru",
+            &SPLIT_ROWS,
+            "12",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+                    .with_max_open_files(1)
+            },
+        );
+    }
+
+    #[test]
+    fn resume_with_atomic_output() {
+        check_resume(
+            "resume-atomic",
+            &SPLIT_ROWS,
+            "12",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+                    .with_max_open_files(2)
+                    .with_atomic_output(true)
+            },
+        );
+    }
+
+    #[test]
+    fn resume_before_the_first_checkpoint() {
+        check_resume(
+            "resume-early",
+            &SPLIT_ROWS,
+            "3",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_existing_files(ExistingFilePolicy::Error)
+            },
+        );
+    }
+
+    #[test]
+    fn resume_restores_totals_and_quarantined_rows() {
+        let dir = test_dir("resume-totals");
+        let input = dir.join("input.csv");
+        fs::write(
+            &input,
+            "key,value\na,1\nb,2,extra\na,3\nb,4\na,5\nb,6,extra\na,7\n",
+        )
+        .unwrap();
+        let input = input.to_string_lossy().into_owned();
+        let out = dir.join("out");
+        let checkpoint = dir.join("checkpoint.csv");
+        let dead_letter = dir.join("rejected.csv");
+        // Key selector errors would be quarantined too, so crash by panicking instead.
+        let writer = |crash: bool| {
+            ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+                .with_key_selector(move |rec: &StringRecord| {
+                    assert!(!crash || &rec[1] != "7", "crash");
+                    rec[0].to_owned()
+                })
+                .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+                .with_output_directory(&out)
+                .with_checkpoint(&checkpoint, 3)
+                .with_malformed_rows(MalformedRowPolicy::Quarantine(dead_letter.clone()))
+        };
+
+        let mut crashed = std::mem::ManuallyDrop::new(writer(true));
+        let crash = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crashed.resume_file(&input).ok();
+        }));
+        assert!(crash.is_err());
+
+        let mut resumed = writer(false);
+        let summary = resumed.resume_file(&input).unwrap();
+        assert_eq!(summary.records_read, 1);
+        let totals = resumed.finish().unwrap();
+        assert_eq!(totals.records_read, 5);
+        assert_eq!(totals.records_skipped, 2);
+        assert_eq!(fs::read_to_string(&dead_letter).unwrap().lines().count(), 2);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Stands in for a compressor: writes its data in upper case and a trailer when dropped.
+    struct Shouting(Box<dyn Write + Send>);
+
+    impl Write for Shouting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write_all(&buf.to_ascii_uppercase())?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Drop for Shouting {
+        fn drop(&mut self) {
+            self.0.write_all(b"#END\n").ok();
+        }
+    }
+
+    #[test]
+    fn checksums_cover_the_stored_file() {
+        use sha2::{Digest, Sha256};
+
+        let dir = test_dir("checksums");
+        fs::write(dir.join("a-0.csv"), "KEY,VALUE\nA,0\n").unwrap();
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let log = completed.clone();
+
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(&dir)
+            .with_existing_files(ExistingFilePolicy::Append)
+            .with_max_open_files(1)
+            .with_checksums(true)
+            .on_create_file(|_, _, file| Ok(Box::new(Shouting(file))))
+            .on_file_completion(move |file| {
+                let checksum = file.checksum.unwrap().to_owned();
+                log.lock()
+                    .unwrap()
+                    .push((file.path.to_owned(), file.bytes, checksum));
+            });
+
+        writer
+            .process_iter(records(&["a:1", "b:2", "a:3"]))
+            .unwrap();
+        writer.finish().unwrap();
+
+        let completed = completed.lock().unwrap();
+        assert_eq!(completed.len(), 2);
+        for (path, bytes, checksum) in completed.iter() {
+            let contents = fs::read(path).unwrap();
+            assert_eq!(*bytes, contents.len() as u64);
+            assert_eq!(*checksum, format!("{:x}", Sha256::digest(&contents)));
+        }
+        assert_eq!(
+            fs::read_to_string(dir.join("a-0.csv")).unwrap(),
+            "KEY,VALUE\nA,0\nA,1\n#END\nA,3\n#END\n"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Writes `count` input files of `key,value` rows to `dir`, each with `rows` rows spread over
+    /// a handful of keys, and returns their paths.
+    fn write_inputs(dir: &Path, count: usize, rows: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|file| {
+                let path = dir.join(format!("input-{file}.csv"));
+                let mut contents = String::from("key,value\n");
+                for row in 0..rows {
+                    contents.push_str(&format!("k{},{file}-{row}\n", row % 7));
+                }
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn parallel_writer(
+        out: &Path,
+    ) -> ShardedWriter<impl KeySelector<Key = String>, impl ShardNaming<String>> {
+        ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(out)
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let dir = test_dir("parallel");
+        let inputs = write_inputs(&dir, 5, 3 * PARALLEL_BATCH_SIZE + 17);
+
+        let mut sequential = parallel_writer(&dir.join("sequential"));
+        let mut expected = ProcessSummary::default();
+        for input in &inputs {
+            expected += sequential.process_file(input.to_str().unwrap()).unwrap();
+        }
+        sequential.finish().unwrap();
+
+        let mut parallel = parallel_writer(&dir.join("parallel"));
+        let summary = parallel.process_files_parallel(&inputs, 3).unwrap();
+        parallel.finish().unwrap();
+
+        assert_eq!(summary, expected);
+
+        // Rows from different inputs are interleaved differently, so compare each file's rows
+        // as a set, after its header.
+        let sorted = |dir: &Path| {
+            read_dir(dir)
+                .into_iter()
+                .map(|(path, contents)| {
+                    let mut lines: Vec<_> = contents.lines().map(str::to_owned).collect();
+                    lines[1..].sort();
+                    (path, lines)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let expected = sorted(&dir.join("sequential"));
+        assert_eq!(expected.len(), 7);
+        assert_eq!(sorted(&dir.join("parallel")), expected);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn parallel_errors_name_the_input_and_stop_the_run() {
+        let dir = test_dir("parallel-errors");
+        let mut inputs = write_inputs(&dir, 4, 20 * PARALLEL_BATCH_SIZE);
+
+        // A missing input fails with the error opening it. The other inputs are large enough to
+        // fill the channel, so this only returns if the workers notice the writer gave up.
+        let missing = dir.join("missing.csv");
+        inputs.insert(1, missing.clone());
+        let mut writer = parallel_writer(&dir.join("missing"));
+        match writer.process_files_parallel(&inputs, 2) {
+            Err(Error::Input { path, source }) => {
+                assert_eq!(path, missing);
+                let Error::Csv(e) = *source else {
+                    panic!("expected a CSV error, got {source:?}");
+                };
+                assert!(
+                    matches!(e.kind(), csv::ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::NotFound),
+                    "{e:?}"
+                );
+            }
+            other => panic!("expected an input error, got {other:?}"),
+        }
+        drop(writer);
+
+        // So does one with a malformed row, under the default policy.
+        let malformed = dir.join("malformed.csv");
+        fs::write(&malformed, "key,value\nk0,1\nk1,2,extra\nk2,3\n").unwrap();
+        inputs[1] = malformed.clone();
+        let mut writer = parallel_writer(&dir.join("malformed"));
+        match writer.process_files_parallel(&inputs, 2) {
+            Err(Error::Input { path, source }) => {
+                assert_eq!(path, malformed);
+                assert!(
+                    matches!(*source, Error::MalformedRow { line: 3, .. }),
+                    "{source:?}"
+                );
+            }
+            other => panic!("expected an input error, got {other:?}"),
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Input with a ragged row, written with quotes the parser would drop, and a row that isn't
+    /// valid UTF-8, ended with CRLF.
+    const MALFORMED_INPUT: &[u8] = b"key,value\na,1\nb,2,\"3\"\n\"d\",4\nc,\xff\r\n";
+
+    fn malformed_rows(
+        dir: &Path,
+        input: &[u8],
+        policy: MalformedRowPolicy,
+    ) -> Result<ProcessSummary, Error> {
+        let path = dir.join("input.csv");
+        fs::write(&path, input).unwrap();
+
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(dir.join("out"))
+            .with_malformed_rows(policy);
+        let summary = writer.process_file(path.to_str().unwrap())?;
+        writer.finish()?;
+        Ok(summary)
+    }
+
+    #[test]
+    fn malformed_rows_fail_fast() {
+        let dir = test_dir("malformed-fail-fast");
+
+        let ragged = malformed_rows(&dir, MALFORMED_INPUT, MalformedRowPolicy::FailFast);
+        assert!(matches!(ragged, Err(Error::MalformedRow { line: 3, .. })));
+
+        let not_utf8 = b"key,value\na,1\nc,\xff\n";
+        let not_utf8 = malformed_rows(&dir, not_utf8, MalformedRowPolicy::FailFast);
+        assert!(matches!(not_utf8, Err(Error::MalformedRow { line: 3, .. })));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn malformed_rows_are_skipped() {
+        let dir = test_dir("malformed-skip");
+
+        let summary = malformed_rows(&dir, MALFORMED_INPUT, MalformedRowPolicy::Skip).unwrap();
+        assert_eq!(summary.records_read, 2);
+        assert_eq!(summary.records_skipped, 2);
+        assert_eq!(
+            read_dir(&dir.join("out")),
+            BTreeMap::from([
+                (PathBuf::from("a-0.csv"), "key,value\na,1\n".to_owned()),
+                (PathBuf::from("d-0.csv"), "key,value\nd,4\n".to_owned()),
+            ])
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn malformed_rows_are_quarantined_as_read() {
+        let dir = test_dir("malformed-quarantine");
+        let dead_letter = dir.join("rejected.csv");
+
+        let policy = MalformedRowPolicy::Quarantine(dead_letter.clone());
+        let summary = malformed_rows(&dir, MALFORMED_INPUT, policy).unwrap();
+        assert_eq!(summary.records_read, 2);
+        assert_eq!(summary.records_skipped, 2);
+
+        let mut rejected = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(&dead_letter)
+            .unwrap();
+        let rejected: Vec<_> = rejected.byte_records().map(Result::unwrap).collect();
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(&rejected[0][0], b"3");
+        assert_eq!(&rejected[0][2], b"b,2,\"3\"");
+        assert_eq!(&rejected[1][0], b"5");
+        assert_eq!(&rejected[1][2], b"c,\xff");
+        assert!(rejected
+            .iter()
+            .all(|row| row.len() == 3 && !row[1].is_empty()));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn paths_differing_in_case_collide_when_case_insensitive() {
+        let dir = test_dir("case-insensitive");
+        let writer = |case_insensitive: bool| {
+            ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+                .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+                .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+                .with_output_directory(dir.join(case_insensitive.to_string()))
+                .with_case_insensitive_paths(case_insensitive)
+        };
+
+        let mut insensitive = writer(true);
+        match insensitive.process_iter(records(&["Foo:1", "foo:2"])) {
+            Err(Error::PathCollision {
+                path,
+                key,
+                sequence: 0,
+                other_key,
+                other_sequence: 0,
+            }) => {
+                assert_eq!(path, dir.join("true").join("foo-0.csv"));
+                assert_eq!((key.as_str(), other_key.as_str()), ("foo", "Foo"));
+            }
+            other => panic!("expected a path collision, got {other:?}"),
+        }
+        drop(insensitive);
+
+        // Only a case-sensitive filesystem can hold both of these.
+        if !dir.join("TRUE").exists() {
+            let mut sensitive = writer(false);
+            sensitive
+                .process_iter(records(&["Foo:1", "foo:2"]))
+                .unwrap();
+            sensitive.finish().unwrap();
+            assert_eq!(read_dir(&dir.join("false")).len(), 2);
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn names_ignoring_the_key_collide() {
+        let dir = test_dir("ignored-key");
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|_: &String, seq| format!("part-{seq}.csv"))
+            .with_output_directory(&dir);
+
+        match writer.process_iter(records(&["a:1", "b:2"])) {
+            Err(Error::PathCollision { key, other_key, .. }) => {
+                assert_eq!((key.as_str(), other_key.as_str()), ("b", "a"))
+            }
+            other => panic!("expected a path collision, got {other:?}"),
+        }
+        drop(writer);
+
+        // The first file is left as it was.
+        assert_eq!(
+            fs::read_to_string(dir.join("part-0.csv")).unwrap(),
+            "key,value\na,1\n"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn next_sequence_skips_paths_already_used() {
+        let dir = test_dir("used-next-sequence");
+        fs::write(dir.join("part-1.csv"), "from before\n").unwrap();
+
+        // Atomic output keeps finished files under temporary names, so only the paths this run
+        // has used tell it that part-0.csv is taken.
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|_: &String, seq| format!("part-{seq}.csv"))
+            .with_output_directory(&dir)
+            .with_existing_files(ExistingFilePolicy::NextSequence)
+            .with_atomic_output(true)
+            .with_case_insensitive_paths(true);
+        writer
+            .process_iter(records(&["a:1", "B:2", "b:3", "a:4"]))
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            read_dir(&dir),
+            BTreeMap::from([
+                (
+                    PathBuf::from("part-0.csv"),
+                    "key,value\na,1\na,4\n".to_owned()
+                ),
+                (PathBuf::from("part-1.csv"), "from before\n".to_owned()),
+                (PathBuf::from("part-2.csv"), "key,value\nB,2\n".to_owned()),
+                (PathBuf::from("part-3.csv"), "key,value\nb,3\n".to_owned()),
+            ])
+        );
+
+        // A name that ignores the sequence number as well has nowhere else to go.
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|_: &String, _| "all.csv".to_owned())
+            .with_output_directory(dir.join("fixed"))
+            .with_existing_files(ExistingFilePolicy::NextSequence);
+        let result = writer.process_iter(records(&["a:1", "b:2"]));
+        assert!(matches!(result, Err(Error::FileExists(path)) if path.ends_with("all.csv")));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn resume_a_run_over_several_inputs() {
+        // Crash part way through the second input, and before its first checkpoint.
+        for crash_at in ["11", "7"] {
+            let dir = test_dir("resume-inputs");
+            let inputs: Vec<String> = [&ROWS[..6], &ROWS[6..]]
+                .iter()
+                .enumerate()
+                .map(|(i, rows)| {
+                    let input_dir = dir.join(format!("input-{i}"));
+                    fs::create_dir_all(&input_dir).unwrap();
+                    write_input(&input_dir, rows)
+                })
+                .collect();
+            let checkpoint = dir.join("checkpoint.csv");
+            let writer = |out: &Path, crash_at| {
+                checkpointed_writer(&dir.join(out), &checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+            };
+
+            let mut expected = writer(Path::new("expected"), None);
+            for input in &inputs {
+                expected.resume_file(input).unwrap();
+            }
+            let expected_totals = expected.finish().unwrap();
+
+            let mut crashed = writer(Path::new("out"), Some(crash_at));
+            crashed.resume_file(&inputs[0]).unwrap();
+            assert!(crashed.resume_file(&inputs[1]).is_err());
+            std::mem::forget(crashed);
+
+            // The same loop picks the run up again, skipping the input it had finished.
+            let mut resumed = writer(Path::new("out"), None);
+            let skipped = resumed.resume_file(&inputs[0]).unwrap();
+            assert_eq!(skipped, ProcessSummary::default());
+            resumed.resume_file(&inputs[1]).unwrap();
+            assert_eq!(resumed.finish().unwrap(), expected_totals);
+
+            assert_eq!(read_dir(&dir.join("out")), read_dir(&dir.join("expected")));
+            assert!(!checkpoint.exists());
+            fs::remove_dir_all(&dir).ok();
+        }
+    }
+
+    #[test]
+    fn resume_with_a_writer_that_writes_a_preamble() {
+        // Only new files get the preamble, so the output can be cut back and appended to.
+        check_resume(
+            "resume-preamble",
+            &SPLIT_ROWS,
+            "12",
+            |_| {},
+            |out, checkpoint, crash_at| {
+                checkpointed_writer(out, checkpoint, crash_at)
+                    .with_output_splitting(FileSplitting::SplitAfterRows(2))
+                    .with_max_open_files(1)
+                    .on_create_file(|_, mode, mut file| {
+                        if mode == OpenMode::Create {
+                            file.write_all(b"# sharded\n")?;
+                        }
+                        Ok(file)
+                    })
+            },
+        );
+    }
+
+    #[test]
+    fn manifest_lists_every_split_file() {
+        use sha2::{Digest, Sha256};
+
+        let dir = test_dir("manifest");
+        let manifest = dir.join("manifest.csv");
+
+        let mut writer = ShardedWriterBuilder::new_with_header(vec!["key", "value;note"])
+            .with_key_selector(|rec: &StringRecord| rec[0].to_owned())
+            .with_output_shard_naming(|key: &String, seq| format!("{key}-{seq}.csv"))
+            .with_output_directory(dir.join("out"))
+            .with_output_delimiter(b';')
+            .with_output_splitting(FileSplitting::SplitAfterRows(2))
+            .with_checksums(true)
+            .with_manifest(&manifest);
+        writer
+            .process_iter(records(&["a:1", "b:2", "a:3", "a:4", "b:5", "a:6", "a:7"]))
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = csv::Reader::from_path(&manifest).unwrap();
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec!["key", "sequence", "path", "rows", "bytes", "sha256", "header"]
+        );
+
+        let mut rows: Vec<StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), read_dir(&dir.join("out")).len());
+        rows.sort_by(|a, b| (&a[0], &a[1]).cmp(&(&b[0], &b[1])));
+        let files: Vec<_> = rows.iter().map(|row| (&row[0], &row[1], &row[3])).collect();
+        assert_eq!(
+            files,
+            [
+                ("a", "0", "2"),
+                ("a", "1", "2"),
+                ("a", "2", "1"),
+                ("b", "0", "2")
+            ]
+        );
+
+        for row in &rows {
+            let contents = fs::read(&row[2]).unwrap();
+            assert_eq!(row[4], contents.len().to_string());
+            assert_eq!(row[5], format!("{:x}", Sha256::digest(&contents)));
+            // The header as written to each file, in the output dialect.
+            assert_eq!(&row[6], "key;\"value;note\"");
+            assert!(contents.starts_with(b"key;\"value;note\"\n"));
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
 }